@@ -0,0 +1,186 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT
+// or http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use core::any::{Any, TypeId};
+use core::fmt;
+use core::mem::forget;
+
+use crate::cc_box_ptr::CcBoxPtr;
+use crate::trace::Trace;
+use crate::{Cc, Weak};
+
+/// A `Trace` value that can be dynamically typed.
+///
+/// This is implemented for every `'static` `Trace` type, and lets you store
+/// values of many different types as `Cc<dyn TraceAny>` and recover the
+/// concrete `Cc<T>` later with `Cc::downcast`.
+///
+/// # Examples
+///
+/// ```
+/// use bacon_rajan_cc::{Cc, TraceAny};
+///
+/// let values: Vec<Cc<dyn TraceAny>> = vec![
+///     Cc::new(5i32).into_any(),
+///     Cc::new(String::from("five")).into_any(),
+/// ];
+///
+/// let strings: Vec<Cc<String>> = values
+///     .into_iter()
+///     .filter_map(|v| v.downcast::<String>().ok())
+///     .collect();
+/// assert_eq!(*strings[0], "five");
+/// ```
+pub trait TraceAny: Trace + Any {
+    /// Get this value as a `&dyn Any`, to borrow it as its concrete type with
+    /// `downcast_ref`.
+    ///
+    /// Note that `Cc<T>` is itself `TraceAny`, so to borrow the contained value
+    /// write `(*cc).as_any()` rather than `cc.as_any()`.
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<T: Trace + Any> TraceAny for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl fmt::Debug for dyn TraceAny {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("TraceAny { .. }")
+    }
+}
+
+impl<T: Trace + Any> Cc<T> {
+    /// Converts this `Cc<T>` into a dynamically typed `Cc<dyn TraceAny>`.
+    ///
+    /// The reference counts are unchanged, and the object is traced and
+    /// collected exactly as before.
+    pub fn into_any(self) -> Cc<dyn TraceAny> {
+        unsafe { Cc::__unsize(self, |p| -> *mut dyn TraceAny { p }) }
+    }
+}
+
+impl Cc<dyn TraceAny> {
+    /// Returns `true` if the contained value is a `T`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use bacon_rajan_cc::Cc;
+    ///
+    /// let five = Cc::new(5i32).into_any();
+    /// assert!(five.is::<i32>());
+    /// assert!(!five.is::<u32>());
+    /// ```
+    #[inline]
+    pub fn is<T: TraceAny>(&self) -> bool {
+        Cc::type_id(self) == TypeId::of::<T>()
+    }
+
+    /// Attempts to downcast the `Cc<dyn TraceAny>` to a concrete type.
+    ///
+    /// On failure the original `Cc<dyn TraceAny>` is returned. In either case
+    /// the strong and weak counts are preserved.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use bacon_rajan_cc::{Cc, TraceAny};
+    ///
+    /// fn print_if_string(value: Cc<dyn TraceAny>) {
+    ///     if let Ok(string) = value.downcast::<String>() {
+    ///         println!("String ({}): {}", string.len(), string);
+    ///     }
+    /// }
+    ///
+    /// let my_string = "Hello World".to_string();
+    /// print_if_string(Cc::new(my_string).into_any());
+    /// print_if_string(Cc::new(0i8).into_any());
+    /// ```
+    pub fn downcast<T: TraceAny>(self) -> Result<Cc<T>, Cc<dyn TraceAny>> {
+        if self.is::<T>() {
+            let ptr = self._ptr.cast();
+            forget(self);
+            Ok(Cc { _ptr: ptr })
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Get the `TypeId` of the contained value's concrete type.
+    #[inline]
+    pub fn type_id(this: &Self) -> TypeId {
+        this.erased().type_id()
+    }
+
+    /// Get the name of the contained value's concrete type, as given by
+    /// `std::any::type_name`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use bacon_rajan_cc::Cc;
+    ///
+    /// let five = Cc::new(5i32).into_any();
+    /// assert_eq!(Cc::type_name(&five), "i32");
+    /// ```
+    #[inline]
+    pub fn type_name(this: &Self) -> &'static str {
+        this.erased().type_name()
+    }
+}
+
+impl Weak<dyn TraceAny> {
+    /// Returns `true` if the referenced value is, or was, a `T`.
+    ///
+    /// This works even after the value has been dropped.
+    #[inline]
+    pub fn is<T: TraceAny>(&self) -> bool {
+        Weak::type_id(self) == TypeId::of::<T>()
+    }
+
+    /// Attempts to downcast the `Weak<dyn TraceAny>` to a concrete type.
+    ///
+    /// On failure the original `Weak<dyn TraceAny>` is returned. This works
+    /// even after the value has been dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use bacon_rajan_cc::Cc;
+    ///
+    /// let five = Cc::new(5i32).into_any();
+    /// let weak = five.downgrade().downcast::<i32>().unwrap();
+    /// assert_eq!(*weak.upgrade().unwrap(), 5);
+    /// ```
+    pub fn downcast<T: TraceAny>(self) -> Result<Weak<T>, Weak<dyn TraceAny>> {
+        if self.is::<T>() {
+            let ptr = self._ptr.cast();
+            forget(self);
+            Ok(Weak { _ptr: ptr })
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Get the `TypeId` of the referenced value's concrete type.
+    #[inline]
+    pub fn type_id(this: &Self) -> TypeId {
+        CcBoxPtr::new(this._ptr).type_id()
+    }
+
+    /// Get the name of the referenced value's concrete type, as given by
+    /// `std::any::type_name`.
+    #[inline]
+    pub fn type_name(this: &Self) -> &'static str {
+        CcBoxPtr::new(this._ptr).type_name()
+    }
+}
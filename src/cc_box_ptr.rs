@@ -7,42 +7,317 @@
 // or http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
-use core::ptr::NonNull;
+use core::alloc::Layout;
+use core::any::{self, TypeId};
+use core::cell::Cell;
+use core::ptr::{self, NonNull};
+use std::alloc::dealloc;
+use std::rc::Rc;
 
-use crate::trace::Trace;
-use crate::CcBoxData;
+use crate::collect::HeapRoots;
+use crate::trace::{Trace, Tracer};
+use crate::{CcBox, CcBoxData};
 
-/// A trait to group all of the operations we need to be able to do on
-/// `CcBox<T>`'s, potentially across different T types.
-pub trait CcBoxPtr: Trace {
-    /// Get this `CcBoxPtr`'s CcBoxData.
-    fn data(&self) -> &CcBoxData;
-    // Get a mutable reference the value inside this `CcBoxPtr`.
-    // We use this for calling Drop on the value instead of calling
-    // it on the `CcBoxPtr` directly, because we want to avoid holding
-    // a mutable reference to the entire type implementing `CcBoxPtr` 
-    // because we may need to access the data during a drop if there's
-    // a self cycle.
-    fn value(&mut self) -> &mut dyn Dropable;
+// Set when a `RefCell` whose contents couldn't be traced, because it's mutably
+// borrowed, is found while tracing a box, see `CcBoxPtr::trace`.
+thread_local!(static SKIPPED_BORROWED: Cell<bool> = const { Cell::new(false) });
+
+/// Record that a `RefCell` being traced is mutably borrowed, and that its
+/// contents were skipped.
+pub(crate) fn skipped_borrowed() {
+    let _ = SKIPPED_BORROWED.try_with(|s| s.set(true));
+}
+
+/// Run `f`, which traces the value of a box, returning `false` if it skipped
+/// the contents of a mutably borrowed `RefCell` or a locked `sync::Mutex`.
+pub(crate) fn traced_all<F: FnOnce()>(f: F) -> bool {
+    let outer = SKIPPED_BORROWED.with(|s| s.replace(false));
+    f();
+    !SKIPPED_BORROWED.with(|s| s.replace(outer))
+}
+
+/// The operations we need to be able to do on `CcBox<T>`'s, potentially across
+/// different T types.
+///
+/// A `CcBox<T>` can hold an unsized `T` (a trait object, a slice or a `str`),
+/// and a `CcBox<[T]>` can't be coerced into a trait object, so instead of a
+/// `dyn` trait every box stores a pointer to one of these tables in its
+/// `CcBoxData` header.
+pub struct CcBoxVTable {
+    trace: unsafe fn(CcBoxPtr, &mut Tracer),
+    drop_value: unsafe fn(CcBoxPtr),
+    layout: unsafe fn(CcBoxPtr) -> Layout,
+    type_id: fn() -> TypeId,
+    type_name: fn() -> &'static str,
+    /// Whether the box stores the length of its slice value in the word in
+    /// front of its header.
+    slice: bool,
+}
+
+impl CcBoxVTable {
+    /// The vtable for a box holding a sized `T`.
+    pub fn sized<T: Trace + 'static>() -> &'static CcBoxVTable {
+        &SizedVTable::<T>::VTABLE
+    }
+
+    /// The vtable for a box holding a `[T]`, whose length is stored in front
+    /// of the box's header.
+    pub fn slice<T: Trace + 'static>() -> &'static CcBoxVTable {
+        &SliceVTable::<T>::VTABLE
+    }
+
+    /// The vtable for a box holding a `str`, which is laid out like a `[u8]`.
+    pub fn str() -> &'static CcBoxVTable {
+        &STR_VTABLE
+    }
+}
+
+impl ::core::fmt::Debug for CcBoxVTable {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        f.write_str("CcBoxVTable")
+    }
+}
+
+struct SizedVTable<T>(::core::marker::PhantomData<T>);
+
+impl<T: Trace + 'static> SizedVTable<T> {
+    const VTABLE: CcBoxVTable = CcBoxVTable {
+        trace: SizedVTable::<T>::trace,
+        drop_value: SizedVTable::<T>::drop_value,
+        layout: SizedVTable::<T>::layout,
+        type_id: TypeId::of::<T>,
+        type_name: any::type_name::<T>,
+        slice: false,
+    };
+
+    unsafe fn trace(ptr: CcBoxPtr, tracer: &mut Tracer) {
+        let b = ptr.0.cast::<CcBox<T>>().as_ptr();
+        (*b).value.trace(tracer);
+    }
+
+    unsafe fn drop_value(ptr: CcBoxPtr) {
+        let b = ptr.0.cast::<CcBox<T>>().as_ptr();
+        ptr::drop_in_place(ptr::addr_of_mut!((*b).value));
+    }
+
+    unsafe fn layout(_ptr: CcBoxPtr) -> Layout {
+        Layout::new::<CcBox<T>>()
+    }
+}
+
+struct SliceVTable<T>(::core::marker::PhantomData<T>);
+
+impl<T: Trace + 'static> SliceVTable<T> {
+    const VTABLE: CcBoxVTable = CcBoxVTable {
+        trace: SliceVTable::<T>::trace,
+        drop_value: SliceVTable::<T>::drop_value,
+        layout: SliceVTable::<T>::layout,
+        type_id: TypeId::of::<[T]>,
+        type_name: any::type_name::<[T]>,
+        slice: true,
+    };
+
+    unsafe fn box_ptr(ptr: CcBoxPtr) -> *mut CcBox<[T]> {
+        let len = ptr.slice_len();
+        ptr::slice_from_raw_parts_mut(ptr.0.as_ptr() as *mut T, len) as *mut CcBox<[T]>
+    }
+
+    unsafe fn trace(ptr: CcBoxPtr, tracer: &mut Tracer) {
+        let b = SliceVTable::<T>::box_ptr(ptr);
+        (*b).value.trace(tracer);
+    }
+
+    unsafe fn drop_value(ptr: CcBoxPtr) {
+        let b = SliceVTable::<T>::box_ptr(ptr);
+        ptr::drop_in_place(ptr::addr_of_mut!((*b).value));
+    }
+
+    unsafe fn layout(ptr: CcBoxPtr) -> Layout {
+        slice_layout::<T>(ptr.slice_len()).expect("slice layout was valid at allocation")
+    }
+}
+
+static STR_VTABLE: CcBoxVTable = CcBoxVTable {
+    trace: SliceVTable::<u8>::trace,
+    drop_value: SliceVTable::<u8>::drop_value,
+    layout: SliceVTable::<u8>::layout,
+    type_id: TypeId::of::<str>,
+    type_name: any::type_name::<str>,
+    slice: true,
+};
+
+/// The layout of a `CcBox<[T]>` holding `len` elements.
+pub fn slice_layout<T>(len: usize) -> Option<Layout> {
+    let array = Layout::array::<T>(len).ok()?;
+    let (layout, _) = Layout::new::<CcBoxData>().extend(array).ok()?;
+    Some(layout.pad_to_align())
 }
 
-// An empty trait object that we can use to call
-// ptr::drop_in_place on.
-pub trait Dropable {
+/// The layout of an allocation holding a box of the given layout, preceded by
+/// `words` words in front of its header, and the offset of the box in it.
+pub fn with_prefix(layout: Layout, words: usize) -> Option<(Layout, usize)> {
+    let prefix = Layout::array::<usize>(words).ok()?;
+    let (layout, offset) = prefix.extend(layout).ok()?;
+    Some((layout.pad_to_align(), offset))
+}
+
+/// A type erased pointer to a `CcBox<T>`, used to add and operate on boxes of
+/// any `T` in the ROOTS table and while tracing.
+///
+/// Boxes of the thread-safe heap in the `sync` module are traced with the same
+/// `Trace` impls, so a `CcBoxPtr` may also point to one of those instead. Such
+/// pointers have their lowest bit set, which is otherwise always clear since
+/// the header is aligned to a `usize`, and each collector skips the boxes that
+/// belong to the other one.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct CcBoxPtr(NonNull<CcBoxData>);
+
+impl CcBoxPtr {
+    /// Erase the type of a pointer to a `CcBox<T>`.
+    #[inline(always)]
+    pub(crate) fn new<T: ?Sized>(ptr: NonNull<CcBox<T>>) -> CcBoxPtr {
+        CcBoxPtr(ptr.cast())
+    }
+
+    /// Get this box's CcBoxData.
+    #[inline(always)]
+    pub(crate) fn data(&self) -> &CcBoxData {
+        unsafe { self.0.as_ref() }
+    }
+
+    /// Tag a pointer to a box of the thread-safe heap.
+    #[inline(always)]
+    pub(crate) fn from_sync(ptr: NonNull<u8>) -> CcBoxPtr {
+        let tagged = ptr.as_ptr().wrapping_add(1);
+        CcBoxPtr(unsafe { NonNull::new_unchecked(tagged.cast()) })
+    }
+
+    /// Get the pointer to a box of the thread-safe heap, if this is one.
+    #[inline(always)]
+    pub(crate) fn as_sync(self) -> Option<NonNull<u8>> {
+        if self.is_sync() {
+            let untagged = self.0.as_ptr().cast::<u8>().wrapping_sub(1);
+            Some(unsafe { NonNull::new_unchecked(untagged) })
+        } else {
+            None
+        }
+    }
+
+    #[inline(always)]
+    fn is_sync(self) -> bool {
+        self.0.as_ptr() as usize & 1 == 1
+    }
+
+    /// Invoke the `Tracer` on each of the `CcBoxPtr`s owned by this box's
+    /// value, leaving out any pointers to the thread-safe heap.
+    ///
+    /// Returns `false` if part of the value couldn't be traced, because it's
+    /// in a `RefCell` that's mutably borrowed. Someone is using the box then,
+    /// so collectors must treat it as referenced from outside.
+    pub(crate) unsafe fn trace(self, tracer: &mut Tracer) -> bool {
+        traced_all(|| {
+            (self.data().vtable.get().trace)(self, &mut |t: CcBoxPtr| {
+                if !t.is_sync() {
+                    tracer(t)
+                }
+            })
+        })
+    }
+
+    /// Drop the value inside this box in place.
+    ///
+    /// We never form a mutable reference to the entire box here, because we
+    /// may need to access the data during the drop if there's a self cycle.
+    pub(crate) unsafe fn drop_value(self) {
+        (self.data().vtable.get().drop_value)(self)
+    }
+
+    /// The `TypeId` of the value this box was created with.
+    pub(crate) fn type_id(self) -> TypeId {
+        (self.data().vtable.get().type_id)()
+    }
+
+    /// The name of the type of the value this box was created with.
+    pub(crate) fn type_name(self) -> &'static str {
+        (self.data().vtable.get().type_name)()
+    }
+
+    /// The address of the value inside this box.
+    pub(crate) fn value_ptr(self) -> *const u8 {
+        let offset = crate::data_offset(self.layout().align());
+        self.0.as_ptr().cast::<u8>().wrapping_add(offset)
+    }
+
+    /// The layout of this box, from its header on.
+    pub(crate) fn layout(self) -> Layout {
+        unsafe { (self.data().vtable.get().layout)(self) }
+    }
+
+    /// The length of this box's slice value. Only valid for slices.
+    unsafe fn slice_len(self) -> usize {
+        *self.0.as_ptr().cast::<usize>().sub(1)
+    }
+
+    /// The number of words in front of this box's header.
+    fn prefix_words(self) -> usize {
+        self.data().vtable.get().slice as usize + self.data().in_heap as usize
+    }
+
+    /// The root buffer of the `CcHeap` this box was allocated in, or `None`
+    /// for the default heap.
+    #[inline]
+    pub(crate) fn heap(&self) -> Option<&Rc<HeapRoots>> {
+        self.heap_ptr().map(|heap| unsafe { &*heap })
+    }
+
+    fn heap_ptr(self) -> Option<*mut Rc<HeapRoots>> {
+        if self.data().in_heap {
+            let words = self.prefix_words();
+            Some(unsafe { self.0.as_ptr().cast::<Rc<HeapRoots>>().sub(words) })
+        } else {
+            None
+        }
+    }
+
+    /// The layout of this box's memory, including the words in front of its
+    /// header, and the offset of the header in it.
+    fn allocation(self) -> (Layout, usize) {
+        with_prefix(self.layout(), self.prefix_words()).expect("box layout was valid at allocation")
+    }
+
+    /// The number of bytes of this box's memory.
+    pub(crate) fn size(self) -> usize {
+        self.allocation().0.size()
+    }
+
+    /// Deallocate this box's memory. The value should already have been
+    /// dropped.
+    pub(crate) unsafe fn deallocate(self) {
+        let (layout, offset) = self.allocation();
+        crate::heap_stats::deallocated(layout.size());
+        match self.heap_ptr() {
+            Some(heap) => {
+                crate::collect::deallocated(Some(&**heap), layout.size());
+                // Let go of the box's heap, the only part of the box that
+                // owns anything besides its value.
+                ptr::drop_in_place(heap);
+            }
+            None => crate::collect::deallocated(None, layout.size()),
+        }
+        dealloc(self.0.cast::<u8>().as_ptr().sub(offset), layout);
+    }
 }
-// Implemented for everything
-impl<T> Dropable for T {}
 
 /// Deallocate the box if possible. `s` should already have been dropped.
-pub unsafe fn free(s: NonNull<dyn CcBoxPtr>) {
-    debug_assert!(s.as_ref().data().strong() == 0);
-    debug_assert!(!s.as_ref().data().buffered());
+pub unsafe fn free(s: CcBoxPtr) {
+    debug_assert!(s.data().strong() == 0);
+    debug_assert!(!s.data().buffered());
 
     // Remove the implicit "strong weak" pointer now that we've destroyed
     // the contents.
-    s.as_ref().data().dec_weak();
+    s.data().dec_weak();
 
-    if s.as_ref().data().weak() == 0 {
-        crate::deallocate(s);
+    if s.data().weak() == 0 {
+        s.deallocate();
     }
 }
@@ -0,0 +1,139 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT
+// or http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use core::fmt;
+use core::ops::Deref;
+use core::ptr::NonNull;
+
+use crate::trace::{Trace, Tracer};
+use crate::Cc;
+
+/// An owning projection of a `Cc<T>`: it keeps the `Cc<T>` alive, and
+/// dereferences to a `U` inside of it, such as one of its fields.
+///
+/// A `CcRef<T, U>` holds a strong reference to the projected object, so it
+/// takes part in cycle collection just like the `Cc<T>` it was made from.
+///
+/// # Examples
+///
+/// ```
+/// use bacon_rajan_cc::{Cc, CcRef, Trace, Tracer};
+///
+/// struct Node {
+///     name: String,
+///     // ...other fields
+/// }
+///
+/// impl Trace for Node {
+///     fn trace(&self, _tracer: &mut Tracer) {}
+/// }
+///
+/// let node = Cc::new(Node { name: "root".to_string() });
+/// let name: CcRef<Node, str> = Cc::map(node, |node| &node.name[..]);
+/// assert_eq!(&*name, "root");
+/// ```
+pub struct CcRef<T: 'static + Trace + ?Sized, U: ?Sized> {
+    owner: Cc<T>,
+    value: NonNull<U>,
+}
+
+impl<T: Trace + ?Sized> Cc<T> {
+    /// Makes a `CcRef<T, U>` for a component of the borrowed data, keeping
+    /// `this` alive for as long as the `CcRef` exists.
+    ///
+    /// This is an associated function that needs to be used as
+    /// `Cc::map(...)`, so that it doesn't interfere with methods of the same
+    /// name on the contents.
+    pub fn map<U: ?Sized, F>(this: Self, f: F) -> CcRef<T, U>
+    where
+        F: FnOnce(&T) -> &U,
+    {
+        let value = NonNull::from(f(&*this));
+        CcRef { owner: this, value }
+    }
+}
+
+impl<T: Trace + ?Sized, U: ?Sized> CcRef<T, U> {
+    /// Makes a new `CcRef` for a component of the projected data, sharing the
+    /// same owner.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use bacon_rajan_cc::{Cc, CcRef};
+    ///
+    /// let pair = Cc::new((1, String::from("two")));
+    /// let second = Cc::map(pair, |pair| &pair.1);
+    /// let bytes: CcRef<(i32, String), [u8]> = CcRef::map(second, |s| s.as_bytes());
+    /// assert_eq!(&*bytes, b"two");
+    /// ```
+    pub fn map<V: ?Sized, F>(this: Self, f: F) -> CcRef<T, V>
+    where
+        F: FnOnce(&U) -> &V,
+    {
+        let value = NonNull::from(f(&*this));
+        CcRef {
+            owner: this.owner,
+            value,
+        }
+    }
+
+    /// Get the `Cc<T>` which owns the projected data.
+    #[inline]
+    pub fn owner(this: &Self) -> &Cc<T> {
+        &this.owner
+    }
+
+    /// Turn this projection back into the `Cc<T>` which owns the data.
+    #[inline]
+    pub fn into_owner(this: Self) -> Cc<T> {
+        this.owner
+    }
+}
+
+impl<T: Trace + ?Sized, U: ?Sized> Deref for CcRef<T, U> {
+    type Target = U;
+
+    #[inline(always)]
+    fn deref(&self) -> &U {
+        if self.owner.strong_count() > 0 {
+            unsafe { self.value.as_ref() }
+        } else {
+            panic!("Invalid access during cycle collection");
+        }
+    }
+}
+
+impl<T: Trace + ?Sized, U: ?Sized> Clone for CcRef<T, U> {
+    #[inline]
+    fn clone(&self) -> CcRef<T, U> {
+        CcRef {
+            owner: self.owner.clone(),
+            value: self.value,
+        }
+    }
+}
+
+impl<T: Trace + ?Sized, U: ?Sized> Trace for CcRef<T, U> {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.owner.trace(tracer);
+    }
+}
+
+impl<T: Trace + ?Sized, U: fmt::Debug + ?Sized> fmt::Debug for CcRef<T, U> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: Trace + ?Sized, U: fmt::Display + ?Sized> fmt::Display for CcRef<T, U> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
@@ -7,20 +7,523 @@
 // or http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
-use core::cell::RefCell;
-use core::ptr::NonNull;
+use core::any::Any;
+use core::cell::{Cell, RefCell};
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem;
+use core::ptr;
+use std::collections::{HashMap, HashSet};
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::thread::LocalKey;
+use std::time::{Duration, Instant};
 
 use crate::cc_box_ptr::{free, CcBoxPtr};
-use crate::Color;
+use crate::trace::Trace;
+use crate::{AllocError, Cc, Color, Weak};
 
-thread_local!(static ROOTS: RefCell<Vec<NonNull<dyn CcBoxPtr>>> = RefCell::new(vec![]));
+// The possible roots of the default heap. Access it with `try_with_roots`.
+thread_local!(static ROOTS: Roots = const { Roots(RefCell::new(Vec::new())) });
+
+// While the destructor of `ROOTS` runs, the buffer it's collecting, which
+// stands in for `ROOTS` as that can't be accessed anymore.
+thread_local!(static EXITING: Cell<*const RefCell<Vec<CcBoxPtr>>> = const { Cell::new(ptr::null()) });
+
+// The policy deciding when to collect automatically. `None` never collects.
+thread_local!(static POLICY: RefCell<Option<Box<dyn CollectPolicy>>> = const { RefCell::new(None) });
+
+// While this is non-zero, automatic collections are held off and recorded in
+// `COLLECT_PENDING` instead, see `defer_auto_collect`.
+thread_local!(static DEFER_DEPTH: Cell<usize> = const { Cell::new(0) });
+thread_local!(static COLLECT_PENDING: Cell<bool> = const { Cell::new(false) });
+
+// Set when `free_dead_roots` was called while a collection or a release was
+// running, to be run once that's done, see `defer_auto_collect`.
+thread_local!(static FREE_DEAD_PENDING: Cell<bool> = const { Cell::new(false) });
+
+// Set while a collection is running, see `collecting`.
+thread_local!(static COLLECTING: Cell<bool> = const { Cell::new(false) });
+
+// The boxes left for the outermost `release` to release in turn, or `None`
+// while no value is being released.
+thread_local!(static RELEASE_QUEUE: RefCell<Option<Vec<CcBoxPtr>>> = const { RefCell::new(None) });
+
+// The memory and the number of boxes of the default heap, and the limits on
+// them.
+thread_local!(static LIMITS: Limits = const { Limits::new() });
+
+// The heaps whose collections were asked for while another one was running.
+thread_local!(static PENDING_HEAPS: RefCell<Vec<Rc<HeapRoots>>> = const { RefCell::new(Vec::new()) });
+
+// The hooks registered with `on_before_collect` and `on_after_collect`, by id.
+type Hooks<A> = RefCell<Vec<(usize, Rc<RefCell<dyn FnMut(A)>>)>>;
+thread_local!(static BEFORE_HOOKS: Hooks<usize> = const { RefCell::new(Vec::new()) });
+thread_local!(static AFTER_HOOKS: Hooks<CollectStats> = const { RefCell::new(Vec::new()) });
+thread_local!(static NEXT_HOOK_ID: Cell<usize> = const { Cell::new(0) });
+
+struct Roots(RefCell<Vec<CcBoxPtr>>);
+
+/// The most collections `Roots::drop` runs, in case the destructors of the
+/// garbage keep making more of it.
+const EXIT_COLLECTIONS: usize = 16;
+
+impl Drop for Roots {
+    /// The thread is exiting, so no one will collect the roots that are left
+    /// if we don't.
+    fn drop(&mut self) {
+        EXITING.with(|e| e.set(&self.0));
+        // Keep going for as long as destructors of the garbage buffer more,
+        // up to a point. A panic here would abort the process, so give up on
+        // the rest instead.
+        let _ = panic::catch_unwind(|| {
+            // The roots of an incremental collection aren't in the buffer.
+            crate::incremental::finish_in_progress();
+            for _ in 0..EXIT_COLLECTIONS {
+                if number_of_roots_buffered() == 0 || collect_cycles_with_stats().freed == 0 {
+                    break;
+                }
+            }
+        });
+        EXITING.with(|e| e.set(ptr::null()));
+        // Whatever is left is leaked.
+        for s in self.0.take() {
+            s.data().buffered.set(false);
+        }
+    }
+}
+
+/// Run `f` with the buffer of possible roots of the default heap, or return
+/// `None` if that's already been destroyed because the thread is exiting.
+pub(crate) fn try_with_roots<R, F: FnOnce(&RefCell<Vec<CcBoxPtr>>) -> R>(f: F) -> Option<R> {
+    let exiting = EXITING.with(|e| e.get());
+    if !exiting.is_null() {
+        // It's only set while `Roots::drop` has it borrowed.
+        return Some(f(unsafe { &*exiting }));
+    }
+    ROOTS.try_with(|roots| f(&roots.0)).ok()
+}
 
 #[doc(hidden)]
-pub fn add_root(box_ptr: NonNull<dyn CcBoxPtr>) {
-    ROOTS.with(|r| {
+pub fn add_root(box_ptr: CcBoxPtr) {
+    if let Some(heap) = heap_of(&box_ptr) {
+        heap.roots.borrow_mut().push(box_ptr);
+        return;
+    }
+    let roots = try_with_roots(|r| {
         let mut vec = r.borrow_mut();
         vec.push(box_ptr);
+        vec.len()
+    });
+    match roots {
+        Some(roots) => {
+            if consult_policy(|policy| policy.root_buffered(roots)) {
+                auto_collect();
+            }
+        }
+        None => collect_exited(box_ptr),
+    }
+}
+
+/// Collect from a possible root that can't be buffered, because `ROOTS` is
+/// gone, right away, since no later collection would. If a collection or a
+/// release is already running, leave it be instead, leaking it if it's
+/// garbage.
+fn collect_exited(s: CcBoxPtr) {
+    // Whatever a panicking `Trace` impl left in the buffer is leaked too.
+    struct Exited(RefCell<Vec<CcBoxPtr>>);
+
+    impl Drop for Exited {
+        fn drop(&mut self) {
+            for s in self.0.take() {
+                s.data().buffered.set(false);
+            }
+        }
+    }
+
+    let roots = Exited(RefCell::new(vec![s]));
+    if !is_collecting() {
+        defer_auto_collect(|| collect(&roots.0, &mut CollectStats::default()));
+    }
+}
+
+/// The `CcHeap` whose buffer `s` is buffered in, or `None` for `ROOTS`.
+fn heap_of(s: &CcBoxPtr) -> Option<&HeapRoots> {
+    s.heap().map(|heap| &**heap).filter(|heap| !heap.detached.get())
+}
+
+/// Run `f` with the buffer that `s` is buffered in, when it is, or return
+/// `None` if that's `ROOTS` and it's gone.
+fn with_buffer<R, F: FnOnce(&RefCell<Vec<CcBoxPtr>>) -> R>(s: &CcBoxPtr, f: F) -> Option<R> {
+    match heap_of(s) {
+        Some(heap) => Some(f(&heap.roots)),
+        None => try_with_roots(f),
+    }
+}
+
+/// Let the policy know that a new `Cc<T>` is about to be allocated, and
+/// collect first if it asks for it.
+pub(crate) fn note_allocation() {
+    if consult_policy(|policy| policy.allocating()) {
+        auto_collect();
+    }
+}
+
+fn consult_policy<F: FnOnce(&mut dyn CollectPolicy) -> bool>(f: F) -> bool {
+    POLICY
+        .try_with(|p| match p.try_borrow_mut() {
+            Ok(mut policy) => policy.as_mut().is_some_and(|policy| f(&mut **policy)),
+            // The policy itself allocated or dropped a `Cc<T>`; don't recurse.
+            Err(_) => false,
+        })
+        .unwrap_or(false)
+}
+
+/// Collect now, unless that's been held off by `defer_auto_collect`.
+fn auto_collect() {
+    if DEFER_DEPTH.with(|d| d.get()) == 0 {
+        collect_cycles();
+    } else {
+        COLLECT_PENDING.with(|p| p.set(true));
+    }
+}
+
+/// Run `f` with automatic collection held off, and then run any collection
+/// that was asked for in the meantime once no more calls to this are active.
+///
+/// This is needed wherever a collection could observe a half-updated object
+/// graph: while a value is being dropped in `Cc::release` (a buffered box with
+/// a strong count of 0 would be freed by `mark_roots` from under its own
+/// destructor) and while a collection is already in progress.
+pub(crate) fn defer_auto_collect<R, F: FnOnce() -> R>(f: F) -> R {
+    struct Defer;
+
+    impl Drop for Defer {
+        fn drop(&mut self) {
+            DEFER_DEPTH.with(|d| d.set(d.get() - 1));
+        }
+    }
+
+    let result = {
+        DEFER_DEPTH.with(|d| d.set(d.get() + 1));
+        let _defer = Defer;
+        f()
+    };
+    if DEFER_DEPTH.with(|d| d.get()) == 0 {
+        if FREE_DEAD_PENDING.with(|p| p.replace(false)) {
+            free_dead_roots();
+        }
+        if COLLECT_PENDING.with(|p| p.replace(false)) {
+            collect_cycles();
+        }
+        let heaps = PENDING_HEAPS
+            .try_with(|p| mem::take(&mut *p.borrow_mut()))
+            .unwrap_or_default();
+        for heap in heaps {
+            heap.pending.set(false);
+            heap.collect();
+        }
+    }
+    result
+}
+
+/// If a collection is running on this thread, or a value is being released,
+/// ask for another one to be run once it's finished and return `true`.
+///
+/// A destructor or `Trace` impl called by the collector must not start a new
+/// collection: the object graph is half updated, and the roots have been taken
+/// out of `ROOTS`. Nor may one called by `release`, as the boxes waiting in
+/// its queue have a strong count of zero but haven't been dropped yet.
+pub(crate) fn defer_if_collecting() -> bool {
+    let collecting = is_collecting();
+    if collecting {
+        COLLECT_PENDING.with(|p| p.set(true));
+    }
+    collecting
+}
+
+fn is_collecting() -> bool {
+    COLLECTING.with(|c| c.get())
+        || RELEASE_QUEUE
+            .try_with(|q| q.borrow().is_some())
+            .unwrap_or(false)
+}
+
+/// Run `f` as a collection, during which `defer_if_collecting` returns `true`,
+/// and then run any collection that was asked for in the meantime.
+pub(crate) fn collecting<R, F: FnOnce() -> R>(f: F) -> R {
+    struct Collecting;
+
+    impl Drop for Collecting {
+        fn drop(&mut self) {
+            COLLECTING.with(|c| c.set(false));
+        }
+    }
+
+    defer_auto_collect(|| {
+        debug_assert!(!COLLECTING.with(|c| c.get()));
+        COLLECTING.with(|c| c.set(true));
+        let _collecting = Collecting;
+        f()
+    })
+}
+
+/// Drop the value of a box whose strong count has just dropped to zero, and
+/// free the box unless it's still buffered, in which case `mark_roots` frees
+/// it later.
+///
+/// Dropping the value can release the boxes it owns in turn. Rather than
+/// recursing into them from inside of its destructor, those are queued, and
+/// the outermost call releases everything in the queue before it returns,
+/// in the same order as recursion would. So dropping a long chain of
+/// `Cc<T>`s takes a bounded amount of stack, and it's all freed by the time
+/// the drop of its head returns.
+///
+/// Destructors that panic don't stop the rest of the queue from being
+/// released; the first panic is re-raised once it's done.
+pub(crate) unsafe fn release(s: CcBoxPtr) {
+    let queued = RELEASE_QUEUE.try_with(|q| {
+        let mut q = q.borrow_mut();
+        match q.as_mut() {
+            Some(queue) => {
+                queue.push(s);
+                true
+            }
+            None => {
+                *q = Some(Vec::new());
+                false
+            }
+        }
     });
+    // If the queue is gone because the thread is exiting, recurse instead.
+    if queued == Ok(true) {
+        return;
+    }
+
+    struct Released;
+
+    impl Drop for Released {
+        fn drop(&mut self) {
+            let _ = RELEASE_QUEUE.try_with(|q| q.borrow_mut().take());
+        }
+    }
+
+    defer_auto_collect(|| {
+        let _released = Released;
+        let mut panic = None;
+        let mut next = Some(s);
+        while let Some(s) = next {
+            let start = queue_len();
+            catch_panic(&mut panic, || s.drop_value());
+            crate::heap_stats::freed_by_count();
+            s.data().color.set(Color::Black);
+            if !s.data().buffered() {
+                free(s);
+            }
+
+            // Release what this value owned before what was queued earlier,
+            // and in the order it was dropped.
+            next = RELEASE_QUEUE
+                .try_with(|q| {
+                    let mut q = q.borrow_mut();
+                    let queue = q.as_mut()?;
+                    queue[start..].reverse();
+                    queue.pop()
+                })
+                .unwrap_or(None);
+        }
+        if let Some(panic) = panic {
+            panic::resume_unwind(panic);
+        }
+    })
+}
+
+fn queue_len() -> usize {
+    RELEASE_QUEUE
+        .try_with(|q| q.borrow().as_ref().map_or(0, Vec::len))
+        .unwrap_or(0)
+}
+
+/// Decides when cycles are collected automatically on the current thread.
+///
+/// Install a policy with `set_collect_policy`. By default no policy is
+/// installed, and cycles are only collected by calling `collect_cycles`.
+///
+/// Automatic collections happen when a `Cc<T>` is dropped or allocated, so
+/// while a policy is installed, `Trace` impls must be prepared to be called at
+/// those points, and shouldn't assume that they only run from an explicit call
+/// to `collect_cycles`.
+///
+/// # Examples
+///
+/// ```
+/// use bacon_rajan_cc::{set_collect_policy, CollectPolicy};
+///
+/// /// Collect when a thousand roots have been buffered, but never more than
+/// /// once every ten allocations.
+/// struct Throttled {
+///     allocations: usize,
+/// }
+///
+/// impl CollectPolicy for Throttled {
+///     fn root_buffered(&mut self, roots: usize) -> bool {
+///         roots >= 1000 && self.allocations >= 10
+///     }
+///
+///     fn allocating(&mut self) -> bool {
+///         self.allocations += 1;
+///         false
+///     }
+///
+///     fn collected(&mut self, _roots: usize, _freed: usize) {
+///         self.allocations = 0;
+///     }
+/// }
+///
+/// set_collect_policy(Throttled { allocations: 0 });
+/// ```
+pub trait CollectPolicy {
+    /// Called after a possible cycle root has been buffered, with the number
+    /// of roots now buffered. Return `true` to collect cycles now.
+    fn root_buffered(&mut self, roots: usize) -> bool {
+        let _ = roots;
+        false
+    }
+
+    /// Called before a new `Cc<T>` is allocated. Return `true` to collect
+    /// cycles now.
+    fn allocating(&mut self) -> bool {
+        false
+    }
+
+    /// Called after every collection, including those started by calling
+    /// `collect_cycles` directly, with the number of roots that were buffered
+    /// and the number of objects that were found to be garbage and freed.
+    fn collected(&mut self, roots: usize, freed: usize) {
+        let _ = (roots, freed);
+    }
+}
+
+/// Set the policy deciding when cycles are collected automatically on the
+/// current thread, replacing the previous one.
+///
+/// # Examples
+///
+/// ```
+/// use bacon_rajan_cc::{set_collect_policy, number_of_roots_buffered, Cc, Never, RootThreshold};
+///
+/// set_collect_policy(RootThreshold::new(2));
+/// drop(Cc::new(1).clone());
+/// assert_eq!(number_of_roots_buffered(), 1);
+///
+/// // Buffering the second root triggers a collection, emptying the buffer.
+/// drop(Cc::new(2).clone());
+/// assert_eq!(number_of_roots_buffered(), 0);
+///
+/// set_collect_policy(Never);
+/// ```
+pub fn set_collect_policy<P: CollectPolicy + 'static>(policy: P) {
+    let old = POLICY.with(|p| p.borrow_mut().replace(Box::new(policy)));
+    // Drop the old policy outside of the borrow, in case it owns a `Cc<T>`.
+    drop(old);
+}
+
+/// A `CollectPolicy` that never collects automatically.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Never;
+
+impl CollectPolicy for Never {}
+
+/// A `CollectPolicy` that collects once a number of possible cycle roots have
+/// been buffered.
+///
+/// The threshold can be made adaptive with `RootThreshold::adaptive`.
+#[derive(Clone, Copy, Debug)]
+pub struct RootThreshold {
+    threshold: usize,
+    min: usize,
+    max: usize,
+}
+
+impl RootThreshold {
+    /// Collect whenever `threshold` roots have been buffered.
+    pub fn new(threshold: usize) -> RootThreshold {
+        RootThreshold::adaptive(threshold, threshold)
+    }
+
+    /// Collect whenever a threshold between `min` and `max` roots have been
+    /// buffered, starting at `min`.
+    ///
+    /// After each collection the threshold is doubled (up to `max`) if less
+    /// than half of the buffered roots turned out to be garbage, since
+    /// collecting that often wasn't worth it, and halved (down to `min`)
+    /// otherwise.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use bacon_rajan_cc::{set_collect_policy, Never, RootThreshold};
+    ///
+    /// set_collect_policy(RootThreshold::adaptive(100, 10_000));
+    /// # set_collect_policy(Never);
+    /// ```
+    pub fn adaptive(min: usize, max: usize) -> RootThreshold {
+        assert!(min <= max, "RootThreshold::adaptive: min > max");
+        RootThreshold {
+            threshold: min,
+            min,
+            max,
+        }
+    }
+
+    /// The number of buffered roots that will trigger the next collection.
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+}
+
+impl CollectPolicy for RootThreshold {
+    fn root_buffered(&mut self, roots: usize) -> bool {
+        roots >= self.threshold
+    }
+
+    fn collected(&mut self, roots: usize, freed: usize) {
+        self.threshold = if freed.saturating_mul(2) < roots {
+            self.threshold.saturating_mul(2).min(self.max)
+        } else {
+            (self.threshold / 2).max(self.min)
+        };
+    }
+}
+
+/// A `CollectPolicy` that collects every time a number of `Cc<T>`s have been
+/// allocated since the last collection.
+#[derive(Clone, Copy, Debug)]
+pub struct AllocationThreshold {
+    threshold: usize,
+    allocations: usize,
+}
+
+impl AllocationThreshold {
+    /// Collect before every `threshold`th allocation since the last
+    /// collection.
+    pub fn new(threshold: usize) -> AllocationThreshold {
+        AllocationThreshold {
+            threshold,
+            allocations: 0,
+        }
+    }
+}
+
+impl CollectPolicy for AllocationThreshold {
+    fn allocating(&mut self) -> bool {
+        self.allocations += 1;
+        self.allocations >= self.threshold
+    }
+
+    fn collected(&mut self, _roots: usize, _freed: usize) {
+        self.allocations = 0;
+    }
 }
 
 /// Return the number of potential cycle roots currently buffered for cycle
@@ -97,20 +600,27 @@ pub fn add_root(box_ptr: NonNull<dyn CcBoxPtr>) {
 /// }
 /// ```
 pub fn number_of_roots_buffered() -> usize {
-    ROOTS.with(|r| r.borrow().len())
+    try_with_roots(|r| r.borrow().len()).unwrap_or(0)
 }
 
 
 /// Free's all the of the roots that have a reference count of 0.
 /// This is much faster than doing a full cycle collection and will
 /// ensure that any memory that was never part of a cycle is freed.
+///
+/// Called while a collection is running, or while the value of a `Cc<T>` is
+/// being dropped, this waits for that to finish, since some of those roots
+/// may not have had their values dropped yet.
 pub fn free_dead_roots() {
-    ROOTS.with(|r| {
+    if is_collecting() {
+        FREE_DEAD_PENDING.with(|p| p.set(true));
+        return;
+    }
+    try_with_roots(|r| {
         let mut v = r.borrow_mut();
         v.retain_mut(|root| {
-            let s: &dyn CcBoxPtr = unsafe { root.as_ref() };
-            if s.data().strong() == 0 {
-                s.data().buffered.set(false);
+            if root.data().strong() == 0 {
+                root.data().buffered.set(false);
                 unsafe { free(*root) };
                 false
             } else {
@@ -120,7 +630,44 @@ pub fn free_dead_roots() {
     });
 }
 
-/// Invoke cycle collection for all `Cc<T>`s on this thread.
+/// A report on a collection, as returned by `collect_cycles_with_stats`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CollectStats {
+    /// The number of possible cycle roots that were buffered.
+    pub roots: usize,
+
+    /// The number of roots that were dropped from the buffer without being
+    /// traced, because they had been used since they were buffered, or their
+    /// value was already gone.
+    pub roots_discarded: usize,
+
+    /// The number of nodes marked Gray by `mark_roots`, that is, every node
+    /// that was traced.
+    pub marked_gray: usize,
+
+    /// The number of those that `scan_roots` found to be live after all.
+    pub rescued: usize,
+
+    /// The number of nodes that were found to be garbage and freed.
+    pub freed: usize,
+
+    /// The number of bytes of memory that were deallocated. Memory of garbage
+    /// that's still pointed to by a `Weak<T>` isn't deallocated until the
+    /// last `Weak<T>` is dropped.
+    pub bytes_freed: usize,
+
+    /// The time spent in `mark_roots`.
+    pub mark_time: Duration,
+
+    /// The time spent in `scan_roots`.
+    pub scan_time: Duration,
+
+    /// The time spent in `collect_roots`, including running destructors.
+    pub collect_time: Duration,
+}
+
+/// Invoke cycle collection for all `Cc<T>`s on this thread, except for those
+/// allocated in a `CcHeap`.
 ///
 /// You may wish to do this when the roots buffer reaches a certain size, when
 /// memory is low, or at opportune moments within your application (such as when
@@ -129,16 +676,37 @@ pub fn free_dead_roots() {
 /// This happens in three phases:
 ///
 /// 1. `mark_roots`: We mark the roots and decrement reference counts as we
-/// go. This is optimistically removing the strong references held by the
-/// potentially dead cycles.
+///    go. This is optimistically removing the strong references held by the
+///    potentially dead cycles.
 ///
 /// 2. `scan_roots`: Then we perform a second traversal which marks the garbage
-/// nodes with a reference count of 0 as White and the non-garbage nodes with a
-/// reference count > 0 as Black. The latter group's reference count is restored
-/// to its previous value from before step (1).
+///    nodes with a reference count of 0 as White and the non-garbage nodes with a
+///    reference count > 0 as Black. The latter group's reference count is restored
+///    to its previous value from before step (1).
 ///
 /// 3. `collect_roots`: Finally, the buffer of possible dead cycle roots is
-/// emptied and members of dead cycles (White nodes) are dropped.
+///    emptied and members of dead cycles (White nodes) are dropped.
+///
+/// While `collect_roots` runs the destructors of the garbage it found, every
+/// member of that garbage has a strong count of zero, whether or not its own
+/// destructor has run yet. So from inside a `Drop` impl, `Cc::is_alive`
+/// returns `false` and `Cc::try_get` returns `None` for any other member of
+/// the garbage (dereferencing it panics), and upgrading a `Weak<T>` to it
+/// returns `None`. Objects that are still referenced from outside of the
+/// garbage keep their strong counts, and stay readable until they are dropped
+/// normally.
+///
+/// Calling `collect_cycles` while a collection is already running on the
+/// current thread, such as from a destructor of the garbage, doesn't start a
+/// nested one: it returns right away, and another collection is run as soon
+/// as the current one is finished. The same goes for calling it from the
+/// destructor of a value whose last `Cc<T>` was dropped: the collection is
+/// run once the drop of that `Cc<T>` returns.
+///
+/// When a thread exits, a final collection is run for the roots still
+/// buffered on it. After that, a `Cc<T>` dropped by the destructor of another
+/// thread local is collected from right away, or leaked if that happens while
+/// a collection is running.
 ///
 /// ```rust
 /// use bacon_rajan_cc::{Cc, Trace, Tracer, collect_cycles};
@@ -207,171 +775,1213 @@ pub fn free_dead_roots() {
 /// }
 /// ```
 pub fn collect_cycles() {
-    mark_roots();
-    scan_roots();
-    collect_roots();
+    collect_cycles_with_stats();
 }
 
-/// Consider every node that's been stored in the buffer since the last
-/// collection. If the node is Purple, then the last operation on it was a
-/// decrement of its reference count, and it hasn't been touched since then. It
-/// is potentially the root of a garbage cycle. Perform a graph traversal and
-/// optimistically decrement reference counts as we go. At the end of the
-/// traversal, anything whose reference count became 0 was part of a garbage
-/// cycle. Anything whose reference count did not become 0 was not part of a
-/// garbage cycle, and we will have to restore its old reference count in
-/// `scan_roots`.
-fn mark_roots() {
-    fn mark_gray(cc_box_ptr: &dyn CcBoxPtr) {
-        if cc_box_ptr.data().color() == Color::Gray {
-            return;
-        }
-
-        cc_box_ptr.data().color.set(Color::Gray);
-
-        cc_box_ptr.trace(&mut |t| {
-            let t = unsafe { t.as_ref() };
-            t.data().dec_strong();
-            mark_gray(t);
-        });
+/// Invoke cycle collection for all `Cc<T>`s on this thread outside of a
+/// `CcHeap`, like `collect_cycles`, and report on what it did.
+///
+/// If a collection is already running on the current thread, or a value is
+/// being dropped, this defers the new one like `collect_cycles` does, and
+/// returns empty stats.
+///
+/// # Examples
+///
+/// ```
+/// use bacon_rajan_cc::{collect_cycles_with_stats, Cc, Trace, Tracer};
+/// use std::cell::RefCell;
+///
+/// struct Node {
+///     next: RefCell<Option<Cc<Node>>>,
+/// }
+///
+/// impl Trace for Node {
+///     fn trace(&self, tracer: &mut Tracer) {
+///         self.next.trace(tracer);
+///     }
+/// }
+///
+/// let node = Cc::new(Node { next: RefCell::new(None) });
+/// *node.next.borrow_mut() = Some(node.clone());
+/// drop(node);
+///
+/// let stats = collect_cycles_with_stats();
+/// assert_eq!(stats.roots, 1);
+/// assert_eq!(stats.freed, 1);
+/// println!("collected {} bytes in {:?}", stats.bytes_freed, stats.collect_time);
+/// ```
+pub fn collect_cycles_with_stats() -> CollectStats {
+    let mut stats = CollectStats::default();
+    if defer_if_collecting() {
+        return stats;
     }
 
-    let old_roots: Vec<_> = ROOTS.with(|r| {
-        let mut v = r.borrow_mut();
-        let drained = v.drain(..);
-        drained.collect()
-    });
+    crate::incremental::finish_in_progress();
 
-    let mut new_roots : Vec<_> = old_roots.into_iter().filter_map(|s| {
-        let keep = unsafe {
-            let box_ptr : &dyn CcBoxPtr = s.as_ref();
-            if box_ptr.data().color() == Color::Purple {
-                mark_gray(box_ptr);
-                true
-            } else {
-                box_ptr.data().buffered.set(false);
+    defer_auto_collect(|| {
+        // Anything the hooks buffer is part of this collection.
+        run_hooks(&BEFORE_HOOKS, number_of_roots_buffered());
+        // And whatever they asked for is done by it. Collections asked for by
+        // destructors of the garbage are run after it.
+        COLLECT_PENDING.with(|p| p.set(false));
 
-                if box_ptr.data().color() == Color::Black && box_ptr.data().strong() == 0 {
-                    free(s);
-                }
+        try_with_roots(|roots| collect(roots, &mut stats));
+    });
+    collected(stats.roots, stats.freed);
+    defer_auto_collect(|| run_hooks(&AFTER_HOOKS, stats));
+    stats
+}
 
-                false
+/// Collect the garbage cycles among the possible roots buffered in `roots`
+/// and what they reach.
+fn collect(roots: &RefCell<Vec<CcBoxPtr>>, stats: &mut CollectStats) {
+    collecting(|| {
+        let mut undo = Undo::default();
+        let start = Instant::now();
+        let traced = panic::catch_unwind(AssertUnwindSafe(|| {
+            mark_roots(roots, stats, &mut undo);
+            let marked = Instant::now();
+            scan_roots(roots, stats, &mut undo);
+            (marked, Instant::now())
+        }));
+        let (marked, scanned) = match traced {
+            Ok(times) => times,
+            Err(panic) => {
+                // A `Trace` impl panicked. Leave the heap as we found it.
+                undo.undo(roots);
+                panic::resume_unwind(panic);
             }
         };
-
-        if keep {
-            Some(s)
-        } else {
-            None
-        }
-    }).collect();
-
-    ROOTS.with(|r| {
-        let mut v = r.borrow_mut();
-        v.append(&mut new_roots);
+        stats.mark_time = marked - start;
+        stats.scan_time = scanned - marked;
+        collect_roots(roots, stats, undo.marked, undo.pinned);
+        stats.collect_time = scanned.elapsed();
     });
 }
 
-/// This is the second traversal, after marking. Color each node in the graph as
-/// White nodes if its reference count is 0 and it is part of a garbage cycle,
-/// or Black if the node is still live.
-fn scan_roots() {
-    fn scan_black(s: &dyn CcBoxPtr) {
-        s.data().color.set(Color::Black);
-        s.trace(&mut |t| {
-            let t = unsafe { t.as_ref() };
-            t.data().strong.set(t.data().strong() + 1);
-            if t.data().color() != Color::Black {
-                scan_black(t);
-            }
-        });
+/// Invoke cycle collection for the `Cc<T>`s that `roots` point to and what
+/// they reach, and report on what it did.
+///
+/// This is for when you know where the garbage might be, such as after
+/// tearing down one part of a program, and don't want to pay for scanning
+/// every possible root that's buffered on the thread. The objects that
+/// `roots` point to are taken as the only possible roots, whether or not
+/// they're buffered. Any other root stays buffered, untouched, for a later
+/// `collect_cycles`, though garbage that's found through `roots` is freed even
+/// if it's buffered, much like with `CcHeap::collect_cycles`.
+///
+/// Objects whose value is already gone are skipped. Those that are alive are
+/// dropped from the buffer whether or not they turn out to be garbage, as by
+/// any other collection. See `Cc::collect_from` to drop a `Cc<T>` and collect
+/// from it in one go.
+///
+/// Like `CcHeap::collect_cycles`, this doesn't consult the `CollectPolicy` or
+/// run the collection hooks. If a collection is already running on the
+/// current thread, or a value is being dropped, this defers a full collection
+/// like `collect_cycles` does, and returns empty stats.
+///
+/// # Examples
+///
+/// ```
+/// use bacon_rajan_cc::{collect_cycles_from, number_of_roots_buffered, Cc, Trace, Tracer};
+/// use std::cell::RefCell;
+///
+/// struct Node {
+///     next: RefCell<Option<Cc<Node>>>,
+/// }
+///
+/// impl Trace for Node {
+///     fn trace(&self, tracer: &mut Tracer) {
+///         self.next.trace(tracer);
+///     }
+/// }
+///
+/// fn cycle() -> Cc<Node> {
+///     let a = Cc::new(Node { next: RefCell::new(None) });
+///     let b = Cc::new(Node { next: RefCell::new(Some(a.clone())) });
+///     *a.next.borrow_mut() = Some(b);
+///     a
+/// }
+///
+/// let (document, other) = (cycle(), cycle());
+/// let weak = document.downgrade();
+/// drop((document, other));
+/// assert_eq!(number_of_roots_buffered(), 2);
+///
+/// let stats = collect_cycles_from(&[weak.clone()]);
+/// assert_eq!(stats.freed, 2);
+/// assert!(weak.upgrade().is_none());
+///
+/// // The other cycle is left for later.
+/// assert_eq!(number_of_roots_buffered(), 1);
+/// # bacon_rajan_cc::collect_cycles();
+/// ```
+pub fn collect_cycles_from<T: Trace + ?Sized>(roots: &[Weak<T>]) -> CollectStats {
+    collect_candidates(roots.iter().filter_map(Weak::erased).collect())
+}
+
+/// Collect from `candidates` as the only possible roots, as described for
+/// `collect_cycles_from`.
+pub(crate) fn collect_candidates(mut candidates: Vec<CcBoxPtr>) -> CollectStats {
+    let mut stats = CollectStats::default();
+    if defer_if_collecting() {
+        return stats;
     }
 
-    fn scan(s: &dyn CcBoxPtr) {
-        if s.data().color() != Color::Gray {
-            return;
-        }
+    crate::incremental::finish_in_progress();
 
-        if s.data().strong() > 0 {
-            scan_black(s);
-        } else {
-            s.data().color.set(Color::White);
-            s.trace(&mut |t| {
-                scan(unsafe { t.as_ref() });
-            });
+    let mut seen = HashSet::new();
+    candidates.retain(|s| s.data().strong() > 0 && seen.insert(*s));
+
+    // Take them out of whichever buffer they're in, so that they're only
+    // buffered in the one made up for them.
+    let buffered: HashSet<CcBoxPtr> = candidates
+        .iter()
+        .copied()
+        .filter(|s| s.data().buffered())
+        .collect();
+    let heap = |s: &CcBoxPtr| heap_of(s).map(|heap| heap as *const HeapRoots);
+    let mut buffers: Vec<CcBoxPtr> = Vec::new();
+    for s in &buffered {
+        if !buffers.iter().any(|b| heap(b) == heap(s)) {
+            buffers.push(*s);
         }
     }
+    for b in &buffers {
+        with_buffer(b, |roots| {
+            roots.borrow_mut().retain(|s| !buffered.contains(s))
+        });
+    }
+    for s in &candidates {
+        s.data().color.set(Color::Purple);
+        s.data().buffered.set(true);
+    }
 
-    ROOTS.with(|r| {
-        let mut v = r.borrow_mut();
-        for s in &mut *v {
-            scan(unsafe { s.as_ref() });
+    // If a `Trace` impl panics, the collection puts the candidates it didn't
+    // get to back in `roots`. They go back to their own buffers from there.
+    struct Candidates(RefCell<Vec<CcBoxPtr>>);
+
+    impl Drop for Candidates {
+        fn drop(&mut self) {
+            for s in self.0.take() {
+                if with_buffer(&s, |roots| roots.borrow_mut().push(s)).is_none() {
+                    s.data().buffered.set(false);
+                }
+            }
         }
-    });
+    }
+
+    let roots = Candidates(RefCell::new(candidates));
+    defer_auto_collect(|| collect(&roots.0, &mut stats));
+    crate::heap_stats::collected(stats.freed);
+    stats
 }
 
-/// Go through all the White roots and their garbage cycles and collect these nodes.
-/// If a White node is still in the roots buffer, then leave it
-/// there. It will be freed in the next collection when we iterate over the
-/// buffer in `mark_roots`.
-fn collect_roots() {
+/// Return the number of roots buffered in the same buffer as `s` would be.
+pub(crate) fn buffered_with(s: CcBoxPtr) -> usize {
+    with_buffer(&s, |roots| roots.borrow().len()).unwrap_or(0)
+}
 
-    // Collecting the nodes into this Vec is a difference from the original
-    // Bacon-Rajan paper. We need this because we have destructors and
-    // running them during traversal will cause cycles to be broken which
-    // ruins the rest of our traversal.
-    let mut white = Vec::new();
+/// Return the roots buffered in the same buffer as `s` would be, from the
+/// `start`th on.
+pub(crate) fn buffered_with_since(s: CcBoxPtr, start: usize) -> Vec<CcBoxPtr> {
+    with_buffer(&s, |roots| {
+        roots.borrow().get(start..).unwrap_or_default().to_vec()
+    })
+    .unwrap_or_default()
+}
 
-    fn collect_white(ptr: NonNull<dyn CcBoxPtr>, white: &mut Vec<NonNull<dyn CcBoxPtr>>) {
-        let s = unsafe { ptr.as_ref() };
-        if s.data().color() == Color::White && !s.data().buffered() {
-            s.data().color.set(Color::Black);
-            s.trace(&mut |t| {
-                collect_white(t, white);
+/// An object of a garbage cycle, as reported by `find_garbage_cycles`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct GarbageObject {
+    /// The name of the type of the value the object was created with.
+    pub type_name: &'static str,
+
+    /// The address of the object's value, as returned by `Cc::as_ptr`.
+    pub address: *const (),
+}
+
+/// A strongly connected component of the garbage found by
+/// `find_garbage_cycles`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct GarbageCycle {
+    /// The objects of the component. Garbage that only hangs off of a cycle
+    /// without being part of one, such as a value owned by a member of the
+    /// cycle, forms a component of its own.
+    pub objects: Vec<GarbageObject>,
+}
+
+/// Find the garbage cycles among the `Cc<T>`s of this thread's default heap,
+/// without freeing them.
+///
+/// This does the trial deletion of `collect_cycles` from the buffered roots,
+/// reports what it found to be garbage, grouped into strongly connected
+/// components, and then puts every color and strong count back as it was. No
+/// destructor is run, and the roots stay buffered for the next collection.
+///
+/// Called while a collection is running on the current thread, or a value is
+/// being dropped, this returns nothing.
+///
+/// # Examples
+///
+/// ```
+/// use bacon_rajan_cc::{collect_cycles, find_garbage_cycles, Cc, Trace, Tracer};
+/// use std::cell::RefCell;
+///
+/// struct Node {
+///     next: RefCell<Option<Cc<Node>>>,
+/// }
+///
+/// impl Trace for Node {
+///     fn trace(&self, tracer: &mut Tracer) {
+///         self.next.trace(tracer);
+///     }
+/// }
+///
+/// let a = Cc::new(Node { next: RefCell::new(None) });
+/// let b = Cc::new(Node { next: RefCell::new(Some(a.clone())) });
+/// *a.next.borrow_mut() = Some(b.clone());
+/// let weak = a.downgrade();
+/// drop((a, b));
+///
+/// let cycles = find_garbage_cycles();
+/// assert_eq!(cycles.len(), 1);
+/// assert_eq!(cycles[0].objects.len(), 2);
+/// assert!(cycles[0].objects[0].type_name.ends_with("Node"));
+///
+/// // Nothing was freed.
+/// assert!(weak.upgrade().is_some());
+/// collect_cycles();
+/// assert!(weak.upgrade().is_none());
+/// ```
+pub fn find_garbage_cycles() -> Vec<GarbageCycle> {
+    try_with_roots(garbage_cycles).unwrap_or_default()
+}
+
+fn garbage_cycles(roots: &RefCell<Vec<CcBoxPtr>>) -> Vec<GarbageCycle> {
+    let white = find_garbage(roots, &[]);
+    components(&white)
+        .into_iter()
+        .map(|component| GarbageCycle {
+            objects: component
+                .into_iter()
+                .map(|i| GarbageObject {
+                    type_name: white[i].type_name(),
+                    address: white[i].value_ptr().cast(),
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+/// Do the trial deletion of a collection from the Purple roots buffered in
+/// `roots`, and from `extra`, and return the boxes it found to be garbage,
+/// after putting back every color, strong count and root as they were.
+pub(crate) fn find_garbage(roots: &RefCell<Vec<CcBoxPtr>>, extra: &[CcBoxPtr]) -> Vec<CcBoxPtr> {
+    if is_collecting() {
+        return Vec::new();
+    }
+
+    collecting(|| {
+        // `mark_roots` drops the other roots from the buffer, and frees those
+        // whose value is gone, so it's only given a copy of the Purple ones.
+        let mut candidates: Vec<CcBoxPtr> = roots
+            .borrow()
+            .iter()
+            .copied()
+            .filter(|s| s.data().color() == Color::Purple)
+            .collect();
+        let saved: Vec<_> = extra
+            .iter()
+            .map(|s| (*s, s.data().color(), s.data().buffered()))
+            .collect();
+        for s in extra {
+            s.data().color.set(Color::Purple);
+            candidates.push(*s);
+        }
+        let candidates = RefCell::new(candidates);
+
+        let mut stats = CollectStats::default();
+        let mut undo = Undo::default();
+        let traced = panic::catch_unwind(AssertUnwindSafe(|| {
+            mark_roots(&candidates, &mut stats, &mut undo);
+            scan_roots(&candidates, &mut stats, &mut undo);
+        }));
+        let white = match traced {
+            Ok(()) => undo
+                .marked
+                .iter()
+                .map(|&(s, _)| s)
+                .filter(|s| s.data().color() == Color::White)
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        undo.undo(&candidates);
+        for (s, color, buffered) in saved {
+            s.data().color.set(color);
+            s.data().buffered.set(buffered);
+        }
+        if let Err(panic) = traced {
+            panic::resume_unwind(panic);
+        }
+        white
+    })
+}
+
+/// Group `nodes` into strongly connected components, only following the
+/// references between them, with Tarjan's algorithm. Returns the indices of
+/// the members of each component.
+fn components(nodes: &[CcBoxPtr]) -> Vec<Vec<usize>> {
+    let index: HashMap<CcBoxPtr, usize> = nodes.iter().enumerate().map(|(i, s)| (*s, i)).collect();
+    let edges: Vec<Vec<usize>> = nodes
+        .iter()
+        .map(|s| {
+            let mut children = Vec::new();
+            unsafe {
+                s.trace(&mut |t| {
+                    if let Some(&j) = index.get(&t) {
+                        children.push(j);
+                    }
+                });
+            }
+            children
+        })
+        .collect();
+
+    let mut order = vec![usize::MAX; nodes.len()];
+    let mut low = vec![0; nodes.len()];
+    let mut on_stack = vec![false; nodes.len()];
+    let mut stack = Vec::new();
+    let mut components = Vec::new();
+    let mut next = 0;
+    // The nodes being visited, with the next of their children to look at.
+    let mut work: Vec<(usize, usize)> = Vec::new();
+    for start in 0..nodes.len() {
+        if order[start] != usize::MAX {
+            continue;
+        }
+        work.push((start, 0));
+        while let Some(&mut (i, ref mut child)) = work.last_mut() {
+            if *child == 0 {
+                order[i] = next;
+                low[i] = next;
+                next += 1;
+                stack.push(i);
+                on_stack[i] = true;
+            }
+            if let Some(&j) = edges[i].get(*child) {
+                *child += 1;
+                if order[j] == usize::MAX {
+                    work.push((j, 0));
+                } else if on_stack[j] {
+                    low[i] = low[i].min(order[j]);
+                }
+                continue;
+            }
+
+            work.pop();
+            if let Some(&(parent, _)) = work.last() {
+                low[parent] = low[parent].min(low[i]);
+            }
+            if low[i] == order[i] {
+                let mut component = Vec::new();
+                while let Some(j) = stack.pop() {
+                    on_stack[j] = false;
+                    component.push(j);
+                    if j == i {
+                        break;
+                    }
+                }
+                components.push(component);
+            }
+        }
+    }
+    components
+}
+
+/// A separate cycle-collected heap, with its own buffer of possible cycle
+/// roots.
+///
+/// `Cc<T>`s allocated with `CcHeap::alloc` or `Cc::new_in` buffer their
+/// possible roots with their heap instead of with the rest of the thread's, so
+/// collecting a heap only scans the roots of its own objects, and
+/// `collect_cycles` leaves them alone. Every other `Cc<T>` belongs to the
+/// thread's default heap. Objects of different heaps may reference each
+/// other: a collection follows references wherever they lead, and garbage
+/// found in another heap is freed by that heap's next collection.
+///
+/// A heap's collections don't consult the `CollectPolicy`, nor do they run the
+/// hooks registered with `on_before_collect` and `on_after_collect`. Those
+/// belong to the default heap.
+///
+/// A heap can be limited in the memory and the number of objects it holds,
+/// with `CcHeap::set_byte_limit` and `CcHeap::set_object_limit`, like the
+/// default heap can with `set_byte_limit` and `set_object_limit`. An
+/// allocation that would go over a limit first collects the heap's cycles,
+/// and if that doesn't make room, `CcHeap::try_alloc` and `Cc::try_new_in`
+/// return an error, while `CcHeap::alloc` and `Cc::new_in` panic.
+///
+/// Dropping a `CcHeap` collects its cycles, and hands whatever objects of it
+/// are still alive over to the default heap.
+///
+/// # Examples
+///
+/// ```
+/// use bacon_rajan_cc::{number_of_roots_buffered, Cc, CcHeap, Trace, Tracer};
+/// use std::cell::RefCell;
+///
+/// struct Node {
+///     next: RefCell<Option<Cc<Node>>>,
+/// }
+///
+/// impl Trace for Node {
+///     fn trace(&self, tracer: &mut Tracer) {
+///         self.next.trace(tracer);
+///     }
+/// }
+///
+/// let heap = CcHeap::new();
+/// let node = heap.alloc(Node { next: RefCell::new(None) });
+/// *node.next.borrow_mut() = Some(node.clone());
+/// drop(node);
+///
+/// assert_eq!(heap.number_of_roots_buffered(), 1);
+/// assert_eq!(number_of_roots_buffered(), 0);
+///
+/// assert_eq!(heap.collect_cycles_with_stats().freed, 1);
+/// assert_eq!(heap.number_of_roots_buffered(), 0);
+/// ```
+pub struct CcHeap {
+    roots: Rc<HeapRoots>,
+}
+
+/// The buffer of possible cycle roots of a `CcHeap`, shared by the heap and
+/// every box allocated in it.
+#[derive(Debug, Default)]
+pub(crate) struct HeapRoots {
+    roots: RefCell<Vec<CcBoxPtr>>,
+    /// Set once the `CcHeap` is dropped, after which its boxes are buffered in
+    /// `ROOTS` instead.
+    detached: Cell<bool>,
+    /// Set while this heap is waiting in `PENDING_HEAPS`.
+    pending: Cell<bool>,
+    limits: Limits,
+}
+
+/// The memory and the number of boxes allocated in a heap that haven't been
+/// deallocated yet, and the limits on them.
+#[derive(Debug, Default)]
+struct Limits {
+    bytes: Cell<usize>,
+    objects: Cell<usize>,
+    byte_limit: Cell<Option<usize>>,
+    object_limit: Cell<Option<usize>>,
+}
+
+impl Limits {
+    const fn new() -> Limits {
+        Limits {
+            bytes: Cell::new(0),
+            objects: Cell::new(0),
+            byte_limit: Cell::new(None),
+            object_limit: Cell::new(None),
+        }
+    }
+
+    /// Account for a new box of `bytes` bytes, running `collect` first if
+    /// that would go over one of the limits. Returns `false`, without
+    /// accounting for it, if it still would.
+    fn reserve<F: FnOnce()>(&self, bytes: usize, collect: F) -> bool {
+        if !self.fits(bytes) {
+            collect();
+            if !self.fits(bytes) {
+                return false;
+            }
+        }
+        self.bytes.set(self.bytes.get() + bytes);
+        self.objects.set(self.objects.get() + 1);
+        true
+    }
+
+    fn fits(&self, bytes: usize) -> bool {
+        let bytes = self.bytes.get().saturating_add(bytes);
+        self.byte_limit.get().is_none_or(|limit| bytes <= limit)
+            && self
+                .object_limit
+                .get()
+                .is_none_or(|limit| self.objects.get() < limit)
+    }
+
+    /// Account for the deallocation of a box of `bytes` bytes.
+    fn deallocated(&self, bytes: usize) {
+        self.bytes.set(self.bytes.get() - bytes);
+        self.objects.set(self.objects.get() - 1);
+    }
+}
+
+/// Account for a new box of `bytes` bytes in `heap`, or in the default heap
+/// if that's `None`, collecting the heap first if that would take it over one
+/// of its limits. Returns `false`, without accounting for it, if it still
+/// would.
+pub(crate) fn reserve(heap: Option<&Rc<HeapRoots>>, bytes: usize) -> bool {
+    match heap {
+        Some(heap) => heap.limits.reserve(bytes, || {
+            heap.collect();
+        }),
+        // Once the thread local is gone, nothing is counted anymore.
+        None => LIMITS
+            .try_with(|l| l.reserve(bytes, collect_cycles))
+            .unwrap_or(true),
+    }
+}
+
+/// Account for the deallocation of a box of `bytes` bytes in `heap`, or in
+/// the default heap if that's `None`.
+pub(crate) fn deallocated(heap: Option<&HeapRoots>, bytes: usize) {
+    match heap {
+        Some(heap) => heap.limits.deallocated(bytes),
+        None => {
+            let _ = LIMITS.try_with(|l| l.deallocated(bytes));
+        }
+    }
+}
+
+/// Limit the memory taken by the `Cc<T>`s of the current thread's default
+/// heap, that is, those not allocated in a `CcHeap`, to `limit` bytes, or lift
+/// the limit with `None`.
+///
+/// An allocation that would go over the limit first collects cycles, and if
+/// that doesn't make room, the fallible constructors such as `Cc::try_new`
+/// return an error. `Cc::new` and every other constructor that can't return
+/// one panic instead, and so do `Cc::make_mut` and `Cc::make_unique` when
+/// they have to clone. Lowering the limit below what's already used doesn't
+/// free anything, it only makes further allocations fail.
+///
+/// # Examples
+///
+/// ```
+/// use bacon_rajan_cc::{bytes_allocated, set_byte_limit, Cc};
+///
+/// set_byte_limit(Some(bytes_allocated()));
+/// let err = Cc::try_new(5).unwrap_err();
+/// assert!(err.is_limit_exceeded());
+///
+/// set_byte_limit(None);
+/// assert!(Cc::try_new(5).is_ok());
+/// ```
+pub fn set_byte_limit(limit: Option<usize>) {
+    LIMITS.with(|l| l.byte_limit.set(limit));
+}
+
+/// Limit the number of `Cc<T>` boxes of the current thread's default heap to
+/// `limit`, or lift the limit with `None`, like `set_byte_limit` does for
+/// their memory.
+pub fn set_object_limit(limit: Option<usize>) {
+    LIMITS.with(|l| l.object_limit.set(limit));
+}
+
+/// Return the number of bytes taken by the `Cc<T>` boxes of the current
+/// thread's default heap, counting those whose value was dropped but which
+/// are kept allocated by a `Weak<T>`.
+pub fn bytes_allocated() -> usize {
+    LIMITS.try_with(|l| l.bytes.get()).unwrap_or(0)
+}
+
+/// Return the number of `Cc<T>` boxes of the current thread's default heap,
+/// counted like `bytes_allocated`.
+pub fn objects_allocated() -> usize {
+    LIMITS.try_with(|l| l.objects.get()).unwrap_or(0)
+}
+
+impl HeapRoots {
+    fn collect(self: &Rc<Self>) -> CollectStats {
+        let mut stats = CollectStats::default();
+        if is_collecting() {
+            // Like `defer_if_collecting`, but for this heap.
+            if !self.pending.replace(true) {
+                let _ = PENDING_HEAPS.try_with(|p| p.borrow_mut().push(self.clone()));
+            }
+            return stats;
+        }
+
+        crate::incremental::finish_in_progress();
+
+        defer_auto_collect(|| collect(&self.roots, &mut stats));
+        crate::heap_stats::collected(stats.freed);
+        stats
+    }
+
+    /// The possible cycle roots buffered by the boxes of this heap.
+    pub(crate) fn roots(&self) -> &RefCell<Vec<CcBoxPtr>> {
+        &self.roots
+    }
+}
+
+impl CcHeap {
+    /// Create a new, empty heap.
+    pub fn new() -> CcHeap {
+        CcHeap {
+            roots: Rc::new(HeapRoots::default()),
+        }
+    }
+
+    /// Allocate `value` in this heap. This is the same as
+    /// `Cc::new_in(self, value)`.
+    pub fn alloc<T: Trace>(&self, value: T) -> Cc<T> {
+        Cc::new_in(self, value)
+    }
+
+    /// Invoke cycle collection for the `Cc<T>`s of this heap, and only scan
+    /// the possible roots buffered by them.
+    ///
+    /// If a collection is already running on the current thread, or a value
+    /// is being dropped, this returns right away, and this heap is collected
+    /// as soon as that's finished, like with `collect_cycles`.
+    pub fn collect_cycles(&self) {
+        self.roots.collect();
+    }
+
+    /// Invoke cycle collection for the `Cc<T>`s of this heap, like
+    /// `CcHeap::collect_cycles`, and report on what it did.
+    pub fn collect_cycles_with_stats(&self) -> CollectStats {
+        self.roots.collect()
+    }
+
+    /// Allocate `value` in this heap, or return an error containing it if that
+    /// would take the heap over one of its limits even after collecting its
+    /// cycles. This is the same as `Cc::try_new_in(self, value)`.
+    pub fn try_alloc<T: Trace>(&self, value: T) -> Result<Cc<T>, AllocError<T>> {
+        Cc::try_new_in(self, value)
+    }
+
+    /// Limit the memory taken by the boxes of this heap to `limit` bytes, or
+    /// lift the limit with `None`.
+    ///
+    /// Lowering the limit below what the heap already uses doesn't free
+    /// anything, it only makes further allocations fail.
+    pub fn set_byte_limit(&self, limit: Option<usize>) {
+        self.roots.limits.byte_limit.set(limit);
+    }
+
+    /// Limit the number of boxes of this heap to `limit`, or lift the limit
+    /// with `None`.
+    pub fn set_object_limit(&self, limit: Option<usize>) {
+        self.roots.limits.object_limit.set(limit);
+    }
+
+    /// Return the number of bytes taken by the boxes of this heap, counting
+    /// those whose value was dropped but which are kept allocated by a
+    /// `Weak<T>`.
+    pub fn bytes_allocated(&self) -> usize {
+        self.roots.limits.bytes.get()
+    }
+
+    /// Return the number of boxes of this heap, counted like
+    /// `CcHeap::bytes_allocated`.
+    pub fn objects_allocated(&self) -> usize {
+        self.roots.limits.objects.get()
+    }
+
+    /// Find the garbage cycles among the `Cc<T>`s of this heap without
+    /// freeing them, like `find_garbage_cycles` does for the default heap.
+    pub fn find_garbage_cycles(&self) -> Vec<GarbageCycle> {
+        garbage_cycles(&self.roots.roots)
+    }
+
+    /// Return the number of possible cycle roots buffered by the `Cc<T>`s of
+    /// this heap.
+    pub fn number_of_roots_buffered(&self) -> usize {
+        self.roots.roots.borrow().len()
+    }
+
+    /// The root buffer to be shared with a new box of this heap.
+    pub(crate) fn roots(&self) -> Rc<HeapRoots> {
+        self.roots.clone()
+    }
+}
+
+impl Default for CcHeap {
+    fn default() -> CcHeap {
+        CcHeap::new()
+    }
+}
+
+impl Drop for CcHeap {
+    fn drop(&mut self) {
+        self.collect_cycles();
+        // The survivors, and whatever they buffer from now on, are left to
+        // `collect_cycles`.
+        self.roots.detached.set(true);
+        let mut roots = mem::take(&mut *self.roots.roots.borrow_mut());
+        if try_with_roots(|r| r.borrow_mut().append(&mut roots)).is_none() {
+            // The thread is exiting, and they'll never be collected.
+            for s in roots {
+                s.data().buffered.set(false);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for CcHeap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CcHeap")
+            .field("roots", &self.number_of_roots_buffered())
+            .field("bytes", &self.bytes_allocated())
+            .field("objects", &self.objects_allocated())
+            .finish()
+    }
+}
+
+/// Unregisters a hook added by `on_before_collect` or `on_after_collect` when
+/// dropped.
+///
+/// Use `mem::forget` to keep the hook for the rest of the thread's lifetime.
+#[must_use = "the hook is unregistered as soon as this is dropped"]
+pub struct CollectHook {
+    id: usize,
+    after: bool,
+    // The hook belongs to the current thread.
+    marker: PhantomData<*const ()>,
+}
+
+impl Drop for CollectHook {
+    fn drop(&mut self) {
+        fn unregister<A>(hooks: &'static LocalKey<Hooks<A>>, id: usize) {
+            // The thread local may already be gone if this is dropped while
+            // the thread exits.
+            let hook = hooks.try_with(|h| {
+                let mut h = h.borrow_mut();
+                let i = h.iter().position(|&(hook_id, _)| hook_id == id)?;
+                Some(h.remove(i))
             });
-            s.data().inc_weak();
-            white.push(ptr);
+            // Drop the hook outside of the borrow, in case it owns a `Cc<T>`.
+            drop(hook);
+        }
+
+        if self.after {
+            unregister(&AFTER_HOOKS, self.id);
+        } else {
+            unregister(&BEFORE_HOOKS, self.id);
         }
     }
+}
 
-    ROOTS.with(|r| {
-        let mut v = r.borrow_mut();
-        for ptr in v.drain(..) {
-            let s: &dyn CcBoxPtr = unsafe { ptr.as_ref() };
-            s.data().buffered.set(false);
-            collect_white(ptr, &mut white);
+impl fmt::Debug for CollectHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CollectHook").finish_non_exhaustive()
+    }
+}
+
+/// Register a hook to be called at the start of every `collect_cycles` on the
+/// current thread, with the number of possible cycle roots buffered, until
+/// the returned `CollectHook` is dropped.
+///
+/// Hooks may allocate, clone and drop `Cc<T>`s. Roots they buffer are taken
+/// into account by the collection that's about to start, and automatic
+/// collections they cause are held off until the hooks are done. A hook that
+/// calls `collect_cycles` itself isn't called again for that collection.
+///
+/// # Examples
+///
+/// ```
+/// use bacon_rajan_cc::{collect_cycles, on_before_collect};
+/// use std::cell::Cell;
+/// use std::rc::Rc;
+///
+/// let runs = Rc::new(Cell::new(0));
+/// let hook = {
+///     let runs = runs.clone();
+///     on_before_collect(move |_roots| runs.set(runs.get() + 1))
+/// };
+///
+/// collect_cycles();
+/// assert_eq!(runs.get(), 1);
+///
+/// drop(hook);
+/// collect_cycles();
+/// assert_eq!(runs.get(), 1);
+/// ```
+pub fn on_before_collect<F: FnMut(usize) + 'static>(hook: F) -> CollectHook {
+    register(&BEFORE_HOOKS, Rc::new(RefCell::new(hook)), false)
+}
+
+/// Register a hook to be called at the end of every `collect_cycles` on the
+/// current thread, with the `CollectStats` of the collection, until the
+/// returned `CollectHook` is dropped.
+///
+/// Like with `on_before_collect`, hooks may allocate, clone and drop
+/// `Cc<T>`s. Automatic collections they cause are run once all of the hooks
+/// are done.
+///
+/// # Examples
+///
+/// ```
+/// use bacon_rajan_cc::{collect_cycles, on_after_collect, Cc, Trace, Tracer};
+/// use std::cell::{Cell, RefCell};
+/// use std::rc::Rc;
+///
+/// struct Node {
+///     next: RefCell<Option<Cc<Node>>>,
+/// }
+///
+/// impl Trace for Node {
+///     fn trace(&self, tracer: &mut Tracer) {
+///         self.next.trace(tracer);
+///     }
+/// }
+///
+/// let freed = Rc::new(Cell::new(0));
+/// let _hook = {
+///     let freed = freed.clone();
+///     on_after_collect(move |stats| freed.set(freed.get() + stats.freed))
+/// };
+///
+/// let node = Cc::new(Node { next: RefCell::new(None) });
+/// *node.next.borrow_mut() = Some(node.clone());
+/// drop(node);
+///
+/// collect_cycles();
+/// assert_eq!(freed.get(), 1);
+/// ```
+pub fn on_after_collect<F: FnMut(CollectStats) + 'static>(hook: F) -> CollectHook {
+    register(&AFTER_HOOKS, Rc::new(RefCell::new(hook)), true)
+}
+
+fn register<A>(
+    hooks: &'static LocalKey<Hooks<A>>,
+    hook: Rc<RefCell<dyn FnMut(A)>>,
+    after: bool,
+) -> CollectHook {
+    let id = NEXT_HOOK_ID.with(|n| n.replace(n.get() + 1));
+    hooks.with(|h| h.borrow_mut().push((id, hook)));
+    CollectHook {
+        id,
+        after,
+        marker: PhantomData,
+    }
+}
+
+/// Call each of the `hooks` with `arg`. Hooks may register and unregister
+/// hooks, so call the ones that were registered when we started, without
+/// holding on to the list.
+fn run_hooks<A: Copy + 'static>(hooks: &'static LocalKey<Hooks<A>>, arg: A) {
+    let hooks: Vec<_> = hooks
+        .try_with(|h| h.borrow().iter().map(|(_, hook)| hook.clone()).collect())
+        .unwrap_or_default();
+    for hook in hooks {
+        if let Ok(mut hook) = hook.try_borrow_mut() {
+            (*hook)(arg);
         }
+    }
+}
+
+/// Let the policy know that a collection starting from `roots` buffered roots
+/// has freed `freed` nodes.
+pub(crate) fn collected(roots: usize, freed: usize) {
+    crate::heap_stats::collected(freed);
+    consult_policy(|policy| {
+        policy.collected(roots, freed);
+        false
     });
+}
 
-    // Run drop on each of nodes. The previous increment of the weak count during traversal will
-    // ensure that all of the memory stays alive during this loop.
-    for i in &white {
-        // Calling drop() will decrement the reference count on any of our live children.
-        // However, during trial deletion the reference count was already decremented
-        // so we'll end up decrementing twice. To avoid that, we increment the count
-        // just before calling drop() so that it balances out. This is another difference
-        // from the original paper caused by having destructors that we need to run.
-        let ptr: &dyn CcBoxPtr = unsafe { i.as_ref() };
-        ptr.trace(&mut |t| {
-            let t = unsafe { t.as_ref() };
-            if t.data().strong() > 0 {
-                t.data().strong.set(t.data().strong() + 1)
+/// Run `f`, and if it panics, keep the panic in `first` unless there already
+/// is one, to be re-raised with `panic::resume_unwind` once we're done.
+/// Returns `false` if `f` panicked.
+pub(crate) fn catch_panic<F: FnOnce()>(first: &mut Option<Box<dyn Any + Send>>, f: F) -> bool {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(()) => true,
+        Err(panic) => {
+            first.get_or_insert(panic);
+            false
+        }
+    }
+}
+
+/// The changes made by `mark_roots` and `scan_roots`, to put everything back
+/// the way it was if a `Trace` impl panics half way through.
+#[derive(Default)]
+struct Undo {
+    /// The roots taken out of `ROOTS`, of which the first `next` have been
+    /// looked at so far, those of them that were kept in the buffer, and
+    /// those that were dropped from it because another root reached them.
+    roots: Vec<CcBoxPtr>,
+    next: usize,
+    kept: Vec<CcBoxPtr>,
+    reached: Vec<CcBoxPtr>,
+    /// Every node that was marked Gray, with its color from before.
+    marked: Vec<(CcBoxPtr, Color)>,
+    /// Every node whose strong count was decremented or incremented, once per
+    /// change.
+    decremented: Vec<CcBoxPtr>,
+    incremented: Vec<CcBoxPtr>,
+    /// The nodes found to be in use while they were traced, which were given
+    /// an extra strong reference, also counted in `incremented`.
+    pinned: Vec<CcBoxPtr>,
+}
+
+impl Undo {
+    fn undo(mut self, buffer: &RefCell<Vec<CcBoxPtr>>) {
+        for s in self.decremented {
+            s.data().strong.set(s.data().strong() + 1);
+        }
+        for s in self.incremented {
+            s.data().strong.set(s.data().strong() - 1);
+        }
+        for (s, color) in self.marked {
+            s.data().color.set(color);
+        }
+        for s in &self.reached {
+            s.data().buffered.set(true);
+        }
+        let mut roots = self.kept;
+        roots.extend_from_slice(&self.reached);
+        roots.extend_from_slice(&self.roots[self.next..]);
+        buffer.borrow_mut().append(&mut roots);
+        self.roots.clear();
+    }
+}
+
+/// Consider every node that's been stored in the buffer since the last
+/// collection. If the node is Purple, then the last operation on it was a
+/// decrement of its reference count, and it hasn't been touched since then. It
+/// is potentially the root of a garbage cycle. Perform a graph traversal and
+/// optimistically decrement reference counts as we go. At the end of the
+/// traversal, anything whose reference count became 0 was part of a garbage
+/// cycle. Anything whose reference count did not become 0 was not part of a
+/// garbage cycle, and we will have to restore its old reference count in
+/// `scan_roots`.
+///
+/// The traversals here and in `scan_roots` keep their own work stacks rather
+/// than recursing, so that long chains of nodes can't overflow the stack.
+fn mark_roots(roots: &RefCell<Vec<CcBoxPtr>>, stats: &mut CollectStats, undo: &mut Undo) {
+    fn mark_gray(stack: &mut Vec<CcBoxPtr>, stats: &mut CollectStats, undo: &mut Undo) {
+        while let Some(cc_box_ptr) = stack.pop() {
+            if cc_box_ptr.data().color() == Color::Gray {
+                continue;
             }
+
+            undo.marked.push((cc_box_ptr, cc_box_ptr.data().color()));
+            cc_box_ptr.data().color.set(Color::Gray);
+            stats.marked_gray += 1;
+
+            let traced = unsafe {
+                cc_box_ptr.trace(&mut |t| {
+                    t.data().dec_strong();
+                    undo.decremented.push(t);
+                    stack.push(t);
+                })
+            };
+            if !traced {
+                // Part of it is borrowed, so it's in use. Count that as a
+                // reference from outside, to keep it and what it reaches
+                // alive.
+                let data = cc_box_ptr.data();
+                data.strong.set(data.strong() + 1);
+                undo.incremented.push(cc_box_ptr);
+                undo.pinned.push(cc_box_ptr);
+            }
+        }
+    }
+
+    undo.roots = mem::take(&mut *roots.borrow_mut());
+    stats.roots = undo.roots.len();
+
+    let mut stack = Vec::new();
+    while let Some(&s) = undo.roots.get(undo.next) {
+        undo.next += 1;
+        if s.data().color() == Color::Purple {
+            undo.kept.push(s);
+            stack.push(s);
+            mark_gray(&mut stack, stats, undo);
+        } else {
+            s.data().buffered.set(false);
+            // Gray roots were already reached from another one.
+            if s.data().color() == Color::Black {
+                stats.roots_discarded += 1;
+            } else {
+                undo.reached.push(s);
+            }
+
+            if s.data().color() == Color::Black && s.data().strong() == 0 {
+                if s.data().weak() == 1 {
+                    stats.bytes_freed += s.size();
+                }
+                unsafe { free(s) };
+            }
+        }
+    }
+
+    roots.borrow_mut().append(&mut undo.kept);
+    undo.roots.clear();
+    undo.next = 0;
+}
+
+/// This is the second traversal, after marking. Color each node in the graph as
+/// White nodes if its reference count is 0 and it is part of a garbage cycle,
+/// or Black if the node is still live.
+fn scan_roots(roots: &RefCell<Vec<CcBoxPtr>>, stats: &mut CollectStats, undo: &mut Undo) {
+    fn scan_black(
+        s: CcBoxPtr,
+        stack: &mut Vec<CcBoxPtr>,
+        stats: &mut CollectStats,
+        undo: &mut Undo,
+    ) {
+        // Nodes are colored Black as they're pushed, so that each one is only
+        // traced once however many references to it there are.
+        s.data().color.set(Color::Black);
+        stats.rescued += 1;
+        stack.push(s);
+        while let Some(s) = stack.pop() {
+            unsafe {
+                s.trace(&mut |t| {
+                    t.data().strong.set(t.data().strong() + 1);
+                    undo.incremented.push(t);
+                    if t.data().color() != Color::Black {
+                        t.data().color.set(Color::Black);
+                        stats.rescued += 1;
+                        stack.push(t);
+                    }
+                });
+            }
+        }
+    }
+
+    fn scan(
+        stack: &mut Vec<CcBoxPtr>,
+        black: &mut Vec<CcBoxPtr>,
+        stats: &mut CollectStats,
+        undo: &mut Undo,
+    ) {
+        while let Some(s) = stack.pop() {
+            if s.data().color() != Color::Gray {
+                continue;
+            }
+
+            if s.data().strong() > 0 {
+                scan_black(s, black, stats, undo);
+            } else {
+                s.data().color.set(Color::White);
+                unsafe {
+                    s.trace(&mut |t| {
+                        stack.push(t);
+                    });
+                }
+            }
+        }
+    }
+
+    let mut stack = Vec::new();
+    let mut black = Vec::new();
+    for s in &*roots.borrow() {
+        stack.push(*s);
+        scan(&mut stack, &mut black, stats, undo);
+    }
+}
+
+/// Empty the roots buffer, and collect every node that `scan_roots` left
+/// White, out of all of the nodes that were `marked` Gray. Then take back the
+/// extra references that `mark_roots` gave to the `pinned` nodes.
+///
+/// Destructors that panic don't stop the rest of the garbage from being
+/// dropped; the first panic is re-raised once it's all freed.
+///
+/// Records the number of nodes that were collected, and the memory freed.
+fn collect_roots(
+    roots: &RefCell<Vec<CcBoxPtr>>,
+    stats: &mut CollectStats,
+    marked: Vec<(CcBoxPtr, Color)>,
+    pinned: Vec<CcBoxPtr>,
+) {
+
+    // Collecting the nodes into this Vec is a difference from the original
+    // Bacon-Rajan paper. We need this because we have destructors and
+    // running them during traversal will cause cycles to be broken which
+    // ruins the rest of our traversal. Every White node was marked Gray, so
+    // we can pick them from there instead of tracing them again.
+    let white: Vec<CcBoxPtr> = marked
+        .into_iter()
+        .map(|(s, _)| s)
+        .filter(|s| s.data().color() == Color::White)
+        .collect();
+
+    for s in roots.borrow_mut().drain(..) {
+        s.data().buffered.set(false);
+    }
+
+    // The increment of the weak count ensures that all of the memory stays
+    // alive while we drop the values.
+    for s in &white {
+        s.data().color.set(Color::Black);
+        s.data().inc_weak();
+    }
+
+    // Dropping the values will decrement the reference count on any of their
+    // live children. However, during trial deletion the reference count was
+    // already decremented so we'll end up decrementing twice. To avoid that,
+    // we increment the counts before calling drop() so that it balances out.
+    // This is another difference from the original paper caused by having
+    // destructors that we need to run.
+    let mut panic = None;
+    let mut leaked = vec![false; white.len()];
+    let mut incremented = Vec::new();
+    for (i, s) in white.iter().enumerate() {
+        incremented.clear();
+        let traced = catch_panic(&mut panic, || unsafe {
+            s.trace(&mut |t| {
+                if t.data().strong() > 0 {
+                    t.data().strong.set(t.data().strong() + 1);
+                    incremented.push(t);
+                }
+            });
         });
-        unsafe { crate::drop_value(*i) };
-        unsafe { free(*i) };
+        if !traced {
+            // We can't tell which references the value holds, so it can't be
+            // dropped. Leak it instead, along with what it references.
+            for t in &incremented {
+                t.data().strong.set(t.data().strong() - 1);
+            }
+            leaked[i] = true;
+        }
+    }
+
+    // Run drop on each of nodes.
+    for (i, s) in white.iter().enumerate() {
+        if !leaked[i] {
+            catch_panic(&mut panic, || unsafe { s.drop_value() });
+        }
+        // Garbage that's still buffered belongs to another heap, whose next
+        // collection frees it, as `mark_roots` does for roots that were
+        // released.
+        if !s.data().buffered() {
+            unsafe { free(*s) };
+        }
     }
 
     // It's now safe to deallocate the memory as long as we are the last weak reference.
     for i in &white {
-        unsafe {
-            // Only deallocate if our weak reference is the only one.
-            if i.as_ref().data().weak() == 1 {
-                crate::deallocate(*i);
-            } else {
-                // undo s.inc_weak() from collect_white
-                i.as_ref().data().dec_weak();
-            }
+        // Only deallocate if our weak reference is the only one.
+        if i.data().weak() == 1 {
+            stats.bytes_freed += i.size();
+            unsafe { i.deallocate() };
+        } else {
+            // undo s.inc_weak() from above
+            i.data().dec_weak();
         }
     }
+    stats.freed = white.len();
+
+    // A pinned node that's left without references was only referenced by
+    // the garbage, so it goes the way of any other node whose last `Cc<T>`
+    // is dropped.
+    for s in pinned {
+        s.data().dec_strong();
+        if s.data().strong() == 0 {
+            catch_panic(&mut panic, || unsafe { release(s) });
+        }
+    }
+
+    if let Some(panic) = panic {
+        panic::resume_unwind(panic);
+    }
 }
@@ -0,0 +1,137 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT
+// or http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Lifetime counters for each thread's heap of `Cc<T>`s.
+//!
+//! The counters are only kept when the `heap-stats` feature is enabled.
+//! Otherwise the hooks below compile down to nothing.
+
+#[cfg(feature = "heap-stats")]
+use core::cell::Cell;
+
+#[cfg(feature = "heap-stats")]
+thread_local!(static STATS: Cell<HeapStats> = const { Cell::new(HeapStats {
+    allocations: 0,
+    freed_by_count: 0,
+    freed_by_collection: 0,
+    live: 0,
+    live_bytes: 0,
+    peak_bytes: 0,
+    collections: 0,
+}) });
+
+/// Counters for the `Cc<T>`s of the current thread, since it started, as
+/// returned by `heap_stats`.
+#[cfg(feature = "heap-stats")]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct HeapStats {
+    /// The number of `Cc<T>` allocations.
+    pub allocations: usize,
+
+    /// The number of values that were freed because their strong count
+    /// dropped to zero.
+    pub freed_by_count: usize,
+
+    /// The number of values that were freed by cycle collection.
+    pub freed_by_collection: usize,
+
+    /// The number of allocations that haven't been deallocated yet. This
+    /// includes the allocations of values that were already freed, but that
+    /// are still pointed to by a `Weak<T>`.
+    pub live: usize,
+
+    /// The number of bytes in those allocations.
+    pub live_bytes: usize,
+
+    /// The highest `live_bytes` has ever been.
+    pub peak_bytes: usize,
+
+    /// The number of cycle collections that were run, including incremental
+    /// ones once they're finished.
+    pub collections: usize,
+}
+
+/// Get the counters for the `Cc<T>`s of the current thread.
+///
+/// Only available with the `heap-stats` feature.
+///
+/// # Examples
+///
+/// ```
+/// use bacon_rajan_cc::{heap_stats, Cc};
+///
+/// let before = heap_stats();
+/// let five = Cc::new(5);
+/// assert_eq!(heap_stats().allocations, before.allocations + 1);
+/// assert_eq!(heap_stats().live, before.live + 1);
+///
+/// drop(five);
+/// assert_eq!(heap_stats().freed_by_count, before.freed_by_count + 1);
+/// assert_eq!(heap_stats().live, before.live);
+/// ```
+#[cfg(feature = "heap-stats")]
+pub fn heap_stats() -> HeapStats {
+    STATS.with(|s| s.get())
+}
+
+#[cfg(feature = "heap-stats")]
+#[inline]
+fn update<F: FnOnce(&mut HeapStats)>(f: F) {
+    // Boxes may be freed while the thread local is being destroyed.
+    let _ = STATS.try_with(|s| {
+        let mut stats = s.get();
+        f(&mut stats);
+        s.set(stats);
+    });
+}
+
+/// Record a new allocation of `bytes` bytes.
+#[inline(always)]
+pub(crate) fn allocated(bytes: usize) {
+    #[cfg(feature = "heap-stats")]
+    update(|s| {
+        s.allocations += 1;
+        s.live += 1;
+        s.live_bytes += bytes;
+        s.peak_bytes = s.peak_bytes.max(s.live_bytes);
+    });
+    #[cfg(not(feature = "heap-stats"))]
+    let _ = bytes;
+}
+
+/// Record the deallocation of `bytes` bytes.
+#[inline(always)]
+pub(crate) fn deallocated(bytes: usize) {
+    #[cfg(feature = "heap-stats")]
+    update(|s| {
+        s.live -= 1;
+        s.live_bytes -= bytes;
+    });
+    #[cfg(not(feature = "heap-stats"))]
+    let _ = bytes;
+}
+
+/// Record that a value was freed because its strong count dropped to zero.
+#[inline(always)]
+pub(crate) fn freed_by_count() {
+    #[cfg(feature = "heap-stats")]
+    update(|s| s.freed_by_count += 1);
+}
+
+/// Record a finished collection that freed `freed` values.
+#[inline(always)]
+pub(crate) fn collected(freed: usize) {
+    #[cfg(feature = "heap-stats")]
+    update(|s| {
+        s.freed_by_collection += freed;
+        s.collections += 1;
+    });
+    #[cfg(not(feature = "heap-stats"))]
+    let _ = freed;
+}
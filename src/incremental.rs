@@ -0,0 +1,420 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT
+// or http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Incremental cycle collection.
+//!
+//! The synchronous collector in `collect.rs` does its trial deletion on the
+//! strong counts themselves, which is only correct as long as nothing else
+//! touches the graph until it's done. Here we instead count references into
+//! the candidate subgraph in a side table, and let the mutator run between
+//! slices of work:
+//!
+//! 1. `Discover`: find every node reachable from the buffered roots.
+//!
+//! 2. `Count`: count the references to each node coming from inside of the
+//!    subgraph.
+//!
+//! 3. `Scan`: nodes with more strong references than internal ones are
+//!    referenced from outside; they, and everything they reach, are live.
+//!
+//! 4. `Collect`: the nodes left over are likely garbage. Since the graph may
+//!    have changed since they were counted, this is checked again in one go,
+//!    only looking at the likely garbage and the references between them,
+//!    before any of it is freed.
+//!
+//! Every node in the subgraph is kept allocated with a weak reference for the
+//! duration of the collection, so that the mutator dropping it between slices
+//! leaves us with a dead box (strong count of 0) instead of a dangling
+//! pointer. No barrier is needed on `Cc::clone` or `Drop`: a change to the
+//! graph can make the side table wrong, but the final check is exact, and it
+//! only ever has to rule out garbage, never find more of it.
+
+use core::cell::RefCell;
+use core::mem;
+use std::collections::{HashMap, HashSet};
+use std::panic;
+
+use crate::cc_box_ptr::{free, CcBoxPtr};
+use crate::collect;
+use crate::Color;
+
+thread_local!(static IN_PROGRESS: RefCell<Option<Incremental>> = const { RefCell::new(None) });
+
+/// The outcome of a slice of incremental cycle collection, as returned by
+/// `collect_cycles_incremental`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CollectProgress {
+    /// The work budget ran out before the collection was finished. Call
+    /// `collect_cycles_incremental` again to continue it.
+    Paused,
+
+    /// The collection is finished.
+    Finished {
+        /// The number of objects that were found to be garbage and freed.
+        freed: usize,
+    },
+}
+
+/// Do a slice of incremental cycle collection on the current thread, tracing
+/// at most about `budget` objects before returning.
+///
+/// If no collection is in progress, this starts a new one by taking every
+/// possible cycle root that's currently buffered. Roots buffered afterwards
+/// are left for the next collection. The program is free to create, change
+/// and drop `Cc<T>`s between calls; the collection takes that into account,
+/// and never frees anything that's still reachable.
+///
+/// Tracing is done in slices, but once the likely garbage has been found, it
+/// is checked and freed in one go, which takes time proportional to the
+/// amount of garbage. Objects that are being looked at by a collection count
+/// an extra weak reference until it is finished. Calling `collect_cycles`
+/// finishes any incremental collection that is in progress.
+///
+/// If a collection is running on the current thread, as when this is called
+/// from a destructor of its garbage, or a value is being dropped, this
+/// returns `Finished { freed: 0 }` right away, and a full collection is run
+/// once the current one, or the drop, is done.
+///
+/// # Examples
+///
+/// ```
+/// use bacon_rajan_cc::{collect_cycles_incremental, Cc, CollectProgress, Trace, Tracer};
+/// use std::cell::RefCell;
+///
+/// struct Node {
+///     next: RefCell<Option<Cc<Node>>>,
+/// }
+///
+/// impl Trace for Node {
+///     fn trace(&self, tracer: &mut Tracer) {
+///         self.next.trace(tracer);
+///     }
+/// }
+///
+/// let first = Cc::new(Node { next: RefCell::new(None) });
+/// let mut last = first.clone();
+/// for _ in 0..100 {
+///     last = Cc::new(Node { next: RefCell::new(Some(last)) });
+/// }
+/// *first.next.borrow_mut() = Some(last);
+/// drop(first);
+///
+/// // Spread the work of collecting the cycle over several frames.
+/// let mut frames = 0;
+/// while collect_cycles_incremental(10) == CollectProgress::Paused {
+///     frames += 1;
+/// }
+/// assert!(frames > 1);
+/// ```
+pub fn collect_cycles_incremental(budget: usize) -> CollectProgress {
+    if collect::defer_if_collecting() {
+        return CollectProgress::Finished { freed: 0 };
+    }
+
+    collect::collecting(|| {
+        // Keep the state out of the thread local while we work on it, so that
+        // `Trace` impls and destructors can't observe it half updated.
+        let state = IN_PROGRESS.with(|s| s.borrow_mut().take());
+        let mut state = match state {
+            Some(state) => state,
+            None => Incremental::start(),
+        };
+        if state.step(budget) {
+            IN_PROGRESS.with(|s| *s.borrow_mut() = Some(state));
+            CollectProgress::Paused
+        } else {
+            CollectProgress::Finished {
+                freed: state.finish(),
+            }
+        }
+    })
+}
+
+/// Finish the incremental collection in progress, if there is one.
+pub(crate) fn finish_in_progress() {
+    let state = IN_PROGRESS.try_with(|s| s.borrow_mut().take());
+    if let Ok(Some(mut state)) = state {
+        collect::collecting(|| {
+            state.step(usize::MAX);
+            state.finish();
+        });
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Phase {
+    Discover,
+    Count,
+    Scan,
+    Collect,
+}
+
+struct Incremental {
+    phase: Phase,
+    /// The number of roots this collection started from.
+    roots: usize,
+    /// The first `candidates` of `nodes` are the roots that were Purple.
+    candidates: usize,
+    /// Every node of the subgraph, each holding a weak reference.
+    nodes: Vec<CcBoxPtr>,
+    index: HashMap<CcBoxPtr, usize>,
+    /// For each node, the number of references to it from within `nodes`.
+    internal: Vec<usize>,
+    live: Vec<bool>,
+    /// Nodes still to be traced in the `Discover` and `Scan` phases.
+    stack: Vec<usize>,
+    /// The next node to look at in the `Count` and `Scan` phases.
+    cursor: usize,
+}
+
+impl Incremental {
+    /// Take the buffered roots for a new collection. As in `mark_roots`, roots
+    /// whose value is already gone are freed, and only the Purple ones are
+    /// candidates.
+    fn start() -> Incremental {
+        let old_roots: Vec<_> =
+            collect::try_with_roots(|r| r.borrow_mut().drain(..).collect()).unwrap_or_default();
+        let mut state = Incremental {
+            phase: Phase::Discover,
+            roots: old_roots.len(),
+            candidates: 0,
+            nodes: Vec::new(),
+            index: HashMap::new(),
+            internal: Vec::new(),
+            live: Vec::new(),
+            stack: Vec::new(),
+            cursor: 0,
+        };
+        for s in old_roots {
+            s.data().buffered.set(false);
+            if s.data().color() == Color::Purple {
+                // Further decrements should buffer it again, for the next
+                // collection.
+                s.data().color.set(Color::Black);
+                state.visit(s);
+            } else if s.data().color() == Color::Black && s.data().strong() == 0 {
+                unsafe { free(s) };
+            }
+        }
+        state.candidates = state.nodes.len();
+        state
+    }
+
+    /// Add `s` to the subgraph, if it isn't already, and schedule it to be
+    /// traced.
+    fn visit(&mut self, s: CcBoxPtr) {
+        if self.index.contains_key(&s) {
+            return;
+        }
+        s.data().inc_weak();
+        let i = self.nodes.len();
+        self.index.insert(s, i);
+        self.nodes.push(s);
+        self.internal.push(0);
+        self.live.push(false);
+        self.stack.push(i);
+    }
+
+    /// Trace the children of the `i`th node that are part of the subgraph, or
+    /// none if its value has been dropped since. Returns `false` if part of
+    /// the value is borrowed, and couldn't be traced.
+    fn trace_in_subgraph<F: FnMut(usize)>(&self, i: usize, mut f: F) -> bool {
+        let s = self.nodes[i];
+        if s.data().strong() == 0 {
+            return true;
+        }
+        unsafe {
+            s.trace(&mut |t| {
+                if let Some(&j) = self.index.get(&t) {
+                    f(j);
+                }
+            })
+        }
+    }
+
+    /// Work through the phases until `budget` nodes have been traced, and
+    /// return `true` if there's more to do.
+    fn step(&mut self, mut budget: usize) -> bool {
+        while self.phase != Phase::Collect {
+            if budget == 0 {
+                return true;
+            }
+            budget -= 1;
+            match self.phase {
+                Phase::Discover => match self.stack.pop() {
+                    Some(i) => {
+                        let s = self.nodes[i];
+                        if s.data().strong() > 0 {
+                            unsafe { s.trace(&mut |t| self.visit(t)) };
+                        }
+                    }
+                    None => self.phase = Phase::Count,
+                },
+                Phase::Count => {
+                    if self.cursor < self.nodes.len() {
+                        let mut internal = mem::take(&mut self.internal);
+                        self.trace_in_subgraph(self.cursor, |j| internal[j] += 1);
+                        self.internal = internal;
+                        self.cursor += 1;
+                    } else {
+                        self.cursor = 0;
+                        self.phase = Phase::Scan;
+                    }
+                }
+                Phase::Scan => {
+                    if let Some(i) = self.stack.pop() {
+                        self.mark_children_live(i);
+                    } else if self.cursor < self.nodes.len() {
+                        let i = self.cursor;
+                        let strong = self.nodes[i].data().strong();
+                        if !self.live[i] && strong > 0 && strong > self.internal[i] {
+                            self.live[i] = true;
+                            self.stack.push(i);
+                        }
+                        self.cursor += 1;
+                    } else {
+                        self.phase = Phase::Collect;
+                    }
+                }
+                Phase::Collect => unreachable!(),
+            }
+        }
+        false
+    }
+
+    fn mark_children_live(&mut self, i: usize) {
+        let mut live = mem::take(&mut self.live);
+        let mut stack = mem::take(&mut self.stack);
+        self.trace_in_subgraph(i, |j| {
+            if !live[j] {
+                live[j] = true;
+                stack.push(j);
+            }
+        });
+        self.live = live;
+        self.stack = stack;
+    }
+
+    /// Check the likely garbage again and free whatever really is garbage,
+    /// then release the subgraph. Returns the number of nodes freed.
+    fn finish(mut self) -> usize {
+        debug_assert_eq!(self.phase, Phase::Collect);
+
+        // Redo the count from scratch for the nodes that look like garbage,
+        // only counting references between them. The rest of the nodes are
+        // live, whatever happened to them since they were scanned.
+        let candidates: Vec<usize> = (0..self.nodes.len())
+            .filter(|&i| !self.live[i] && self.nodes[i].data().strong() > 0)
+            .collect();
+        for &i in &candidates {
+            self.internal[i] = 0;
+        }
+        // Those that are borrowed are in use, and so are live.
+        let mut borrowed = HashSet::new();
+        for &i in &candidates {
+            let mut internal = mem::take(&mut self.internal);
+            let live = &self.live;
+            let traced = self.trace_in_subgraph(i, |j| {
+                if !live[j] {
+                    internal[j] += 1;
+                }
+            });
+            if !traced {
+                borrowed.insert(i);
+            }
+            self.internal = internal;
+        }
+        for &i in &candidates {
+            let strong = self.nodes[i].data().strong();
+            if !self.live[i] && (strong != self.internal[i] || borrowed.contains(&i)) {
+                self.live[i] = true;
+                self.stack.push(i);
+            }
+        }
+        while let Some(i) = self.stack.pop() {
+            self.mark_children_live(i);
+        }
+
+        // Whatever is left is only referenced from itself, and so is garbage.
+        let white: Vec<CcBoxPtr> = candidates
+            .into_iter()
+            .filter(|&i| !self.live[i])
+            .map(|i| self.nodes[i])
+            .collect();
+        let freed = white.len();
+
+        let mut panic = None;
+        collect::defer_auto_collect(|| unsafe {
+            if white.iter().any(|s| s.data().buffered()) {
+                let white: HashSet<_> = white.iter().collect();
+                collect::try_with_roots(|r| {
+                    r.borrow_mut().retain(|s| {
+                        let keep = !white.contains(s);
+                        if !keep {
+                            s.data().buffered.set(false);
+                        }
+                        keep
+                    })
+                });
+            }
+            // All of their strong references are about to be dropped along
+            // with them. Zeroing the counts first makes dropping those
+            // references a no-op, and makes all of the garbage look dead to
+            // its own destructors, like in `collect_roots`.
+            for s in &white {
+                s.data().strong.set(0);
+                s.data().color.set(Color::Black);
+            }
+            // Keep going if a destructor panics, like `collect_roots` does.
+            // Garbage still buffered by a `CcHeap` is freed by its next
+            // collection.
+            for s in &white {
+                collect::catch_panic(&mut panic, || s.drop_value());
+                if !s.data().buffered() {
+                    free(*s);
+                }
+            }
+        });
+
+        for s in self.nodes.drain(..) {
+            s.data().dec_weak();
+            if s.data().weak() == 0 {
+                unsafe { s.deallocate() };
+            }
+        }
+
+        collect::collected(self.roots, freed);
+        if let Some(panic) = panic {
+            panic::resume_unwind(panic);
+        }
+        freed
+    }
+}
+
+impl Drop for Incremental {
+    /// Put the roots back if the collection is dropped before it's finished,
+    /// as when a `Trace` impl panics or the thread exits in the middle of it,
+    /// so that the next collection, or `Roots::drop`, picks them up.
+    fn drop(&mut self) {
+        for (i, s) in self.nodes.drain(..).enumerate() {
+            if i < self.candidates && s.data().strong() > 0 && !s.data().buffered() {
+                s.data().color.set(Color::Purple);
+                s.data().buffered.set(true);
+                if collect::try_with_roots(|r| r.borrow_mut().push(s)).is_none() {
+                    // Too late, it's leaked like the rest of the roots.
+                    s.data().buffered.set(false);
+                }
+            }
+            s.data().dec_weak();
+            if s.data().weak() == 0 {
+                unsafe { s.deallocate() };
+            }
+        }
+    }
+}
@@ -0,0 +1,1033 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT
+// or http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Thread-safe reference-counted boxes, with concurrent cycle collection.
+//!
+//! `sync::Cc<T>` is to `Cc<T>` what `Arc<T>` is to `Rc<T>`: it can be sent to
+//! and shared between threads, and all of the `sync::Cc<T>`s of a process
+//! live in one heap, whose cycles are collected by calling
+//! `sync::collect_cycles` from any thread. That is typically done from a
+//! background thread, while the other threads keep working with their
+//! `sync::Cc<T>`s, following the concurrent variant of the algorithm from
+//! Bacon and Rajan's paper:
+//!
+//! 1. Possible roots (boxes whose strong count was decremented to a non-zero
+//!    value) are taken from a global buffer, and everything they reach is
+//!    traced. Trial deletion is done on "cyclic reference counts" kept by the
+//!    collector, rather than on the strong counts, which the other threads
+//!    keep changing. This finds candidate cycles, colored Orange.
+//!
+//! 2. Each candidate cycle is checked with the Σ-test and the Δ-test (its
+//!    members being colored Red while they're being checked). The Σ-test
+//!    checks that all of the strong references to members of the cycle come
+//!    from members of the cycle, and the Δ-test checks that no strong count
+//!    changed while doing so, and that none of the `sync::Mutex`es in the
+//!    cycle was locked in the meantime, which could have moved a reference
+//!    without changing any strong count. Where the paper relies on epochs to get a consistent view
+//!    of the counts, we read them twice and compare: each count is stored
+//!    together with a version that's bumped on every change.
+//!
+//! 3. Members of a cycle that passed both tests are marked, which makes any
+//!    `sync::Weak<T>` to them fail to upgrade, and then freed. If either test
+//!    failed, the candidates are buffered again to be looked at by the next
+//!    collection.
+//!
+//! Values in a `sync::Cc<T>` are traced by the collector while other threads
+//! may be using them, so they must only change the `Cc<T>`s they trace while
+//! holding a `sync::Mutex` guard; in particular, `std::sync::RwLock`s are not
+//! traced by the collector of this heap, which treats the `sync::Cc<T>`s in
+//! them as referenced from outside. Locking a `sync::Mutex` holds off
+//! validating the candidate cycles it's part of until it is unlocked, while
+//! the rest of the heap is collected as usual.
+//!
+//! # Examples
+//!
+//! ```
+//! use bacon_rajan_cc::sync::{self, Cc, Mutex};
+//! use bacon_rajan_cc::{Trace, Tracer};
+//! use std::thread;
+//!
+//! struct Node {
+//!     next: Mutex<Option<Cc<Node>>>,
+//! }
+//!
+//! impl Trace for Node {
+//!     fn trace(&self, tracer: &mut Tracer) {
+//!         self.next.trace(tracer);
+//!     }
+//! }
+//!
+//! let a = Cc::new(Node { next: Mutex::new(None) });
+//! let weak = a.downgrade();
+//! thread::spawn(move || {
+//!     // Make a cycle on another thread.
+//!     *a.next.lock().unwrap() = Some(a.clone());
+//! })
+//! .join()
+//! .unwrap();
+//!
+//! // And collect it from a third one.
+//! thread::spawn(sync::collect_cycles).join().unwrap();
+//! assert!(weak.upgrade().is_none());
+//! ```
+
+use core::any::Any;
+use core::cell::{Cell, RefCell};
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+use core::sync::atomic::Ordering::SeqCst;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize};
+use std::alloc::{dealloc, Layout};
+use std::collections::HashMap;
+use std::panic;
+use std::sync::{self as std_sync, LockResult, PoisonError, TryLockError};
+
+use crate::cc_box_ptr::CcBoxPtr;
+use crate::collect::catch_panic;
+use crate::trace::{Trace, Tracer};
+use crate::Color;
+
+// A box's `state` holds its strong count in the low 31 bits, the `MARKED` flag,
+// and a version in the high 32 bits that's bumped on every change to the
+// strong count, so that a single load tells whether the count was changed
+// since a previous one.
+const STRONG: u64 = 1;
+const MARKED: u64 = 1 << 31;
+const COUNT: u64 = MARKED - 1;
+const VERSION: u64 = 1 << 32;
+
+#[inline]
+fn count(state: u64) -> u64 {
+    state & COUNT
+}
+
+// The buffer of possible cycle roots. Each entry holds a weak reference.
+static ROOTS: std_sync::Mutex<Vec<BoxPtr>> = std_sync::Mutex::new(Vec::new());
+
+// Only one collection runs at a time.
+static COLLECTOR: std_sync::Mutex<()> = std_sync::Mutex::new(());
+
+// Whether the current thread is tracing for this heap's collector, or running
+// a collection at all.
+thread_local!(static TRACING: Cell<bool> = const { Cell::new(false) });
+thread_local!(static COLLECTING: Cell<bool> = const { Cell::new(false) });
+
+// The guards of each `sync::Mutex` traced while validating candidate cycles,
+// see `Collection::validate`, or `None` while not validating.
+thread_local!(static VALIDATING: RefCell<Option<Validating>> = const { RefCell::new(None) });
+
+/// Whether the current thread is tracing values for the collector of the
+/// thread-safe heap, which must not look into containers whose contents can
+/// change without going through a `sync::Mutex`.
+pub(crate) fn is_tracing() -> bool {
+    TRACING.with(|t| t.get())
+}
+
+fn roots() -> std_sync::MutexGuard<'static, Vec<BoxPtr>> {
+    ROOTS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[repr(C)]
+struct SyncBox<T> {
+    data: SyncBoxData,
+    value: T,
+}
+
+struct SyncBoxData {
+    state: AtomicU64,
+    weak: AtomicUsize,
+    buffered: AtomicBool,
+    vtable: &'static SyncVTable,
+}
+
+struct SyncVTable {
+    trace: unsafe fn(BoxPtr, &mut Tracer),
+    drop_value: unsafe fn(BoxPtr),
+    layout: Layout,
+}
+
+struct VTableFor<T>(PhantomData<T>);
+
+impl<T: Trace + Send + Sync + 'static> VTableFor<T> {
+    const VTABLE: SyncVTable = SyncVTable {
+        trace: VTableFor::<T>::trace,
+        drop_value: VTableFor::<T>::drop_value,
+        layout: Layout::new::<SyncBox<T>>(),
+    };
+
+    unsafe fn trace(ptr: BoxPtr, tracer: &mut Tracer) {
+        let b = ptr.0.cast::<SyncBox<T>>().as_ptr();
+        (*b).value.trace(tracer);
+    }
+
+    unsafe fn drop_value(ptr: BoxPtr) {
+        let b = ptr.0.cast::<SyncBox<T>>().as_ptr();
+        ptr::drop_in_place(ptr::addr_of_mut!((*b).value));
+    }
+}
+
+/// A type erased pointer to a `SyncBox<T>`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+struct BoxPtr(NonNull<SyncBoxData>);
+
+// Boxes are only ever touched through atomics, or by the collector once it
+// has made sure that nothing else can reach them.
+unsafe impl Send for BoxPtr {}
+
+impl BoxPtr {
+    #[inline(always)]
+    fn data(&self) -> &SyncBoxData {
+        unsafe { self.0.as_ref() }
+    }
+
+    /// Call `f` on each of the `sync::Cc<T>`s owned by this box's value.
+    ///
+    /// Returns `false` if some of them were skipped because they're behind a
+    /// locked `sync::Mutex`.
+    fn trace_children<F: FnMut(BoxPtr)>(self, mut f: F) -> bool {
+        TRACING.with(|t| t.set(true));
+        let traced = crate::cc_box_ptr::traced_all(|| unsafe {
+            (self.data().vtable.trace)(self, &mut |t: CcBoxPtr| {
+                if let Some(ptr) = t.as_sync() {
+                    f(BoxPtr(ptr.cast()));
+                }
+            })
+        });
+        TRACING.with(|t| t.set(false));
+        traced
+    }
+
+    /// Take a strong reference, unless the value is gone or about to be.
+    fn try_pin(self) -> bool {
+        let state = &self.data().state;
+        let mut old = state.load(SeqCst);
+        loop {
+            if count(old) == 0 || old & MARKED != 0 {
+                return false;
+            }
+            match state.compare_exchange_weak(old, old + VERSION + STRONG, SeqCst, SeqCst) {
+                Ok(_) => return true,
+                Err(actual) => old = actual,
+            }
+        }
+    }
+
+    fn inc_strong(self) {
+        let old = self.data().state.fetch_add(VERSION + STRONG, SeqCst);
+        if count(old) == COUNT - 1 {
+            std::process::abort();
+        }
+    }
+
+    /// Drop a strong reference, returning `true` if it was the last one.
+    fn dec_strong(self) -> bool {
+        let old = self.data().state.fetch_add(VERSION - STRONG, SeqCst);
+        count(old) == 1
+    }
+
+    /// Drop a strong reference taken by the collector, without buffering the
+    /// box as a possible root.
+    fn unpin(self) {
+        if self.dec_strong() {
+            unsafe { self.release() };
+        }
+    }
+
+    /// Drop the value after its last strong reference is gone.
+    unsafe fn release(self) {
+        (self.data().vtable.drop_value)(self);
+        self.release_weak();
+    }
+
+    unsafe fn release_weak(self) {
+        if self.data().weak.fetch_sub(1, SeqCst) == 1 {
+            dealloc(self.0.as_ptr().cast(), self.data().vtable.layout);
+        }
+    }
+
+    /// The number of `sync::Weak<T>`s to this box, leaving out the implicit
+    /// one of the strong references and that of the root buffer.
+    fn weak_count(self) -> usize {
+        let buffered = self.data().buffered.load(SeqCst) as usize;
+        // The flag is set before the weak count is bumped.
+        self.data().weak.load(SeqCst).saturating_sub(1 + buffered)
+    }
+
+    /// Whether the value can still be reached through a `sync::Weak<T>`.
+    fn is_alive(self) -> bool {
+        let state = self.data().state.load(SeqCst);
+        count(state) != 0 && state & MARKED == 0
+    }
+
+    /// Add the box to the buffer of possible roots, unless it already is.
+    fn buffer(self) {
+        if !self.data().buffered.swap(true, SeqCst) {
+            self.data().weak.fetch_add(1, SeqCst);
+            roots().push(self);
+        }
+    }
+}
+
+/// A thread-safe reference-counted pointer, whose cycles are collected by
+/// `sync::collect_cycles`.
+///
+/// See the [module level documentation](./) for more.
+pub struct Cc<T: Trace + Send + Sync + 'static> {
+    ptr: NonNull<SyncBox<T>>,
+    marker: PhantomData<SyncBox<T>>,
+}
+
+unsafe impl<T: Trace + Send + Sync> Send for Cc<T> {}
+unsafe impl<T: Trace + Send + Sync> Sync for Cc<T> {}
+
+impl<T: Trace + Send + Sync> Cc<T> {
+    /// Constructs a new `sync::Cc<T>`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use bacon_rajan_cc::sync::Cc;
+    ///
+    /// let five = Cc::new(5);
+    /// ```
+    pub fn new(value: T) -> Cc<T> {
+        let b = Box::new(SyncBox {
+            data: SyncBoxData {
+                state: AtomicU64::new(STRONG),
+                // The implicit weak pointer owned by all the strong pointers.
+                weak: AtomicUsize::new(1),
+                buffered: AtomicBool::new(false),
+                vtable: &VTableFor::<T>::VTABLE,
+            },
+            value,
+        });
+        Cc {
+            ptr: NonNull::from(Box::leak(b)),
+            marker: PhantomData,
+        }
+    }
+
+    /// Downgrades the `sync::Cc<T>` to a `sync::Weak<T>` reference.
+    pub fn downgrade(&self) -> Weak<T> {
+        self.box_ptr().data().weak.fetch_add(1, SeqCst);
+        Weak {
+            ptr: self.ptr,
+            marker: PhantomData,
+        }
+    }
+
+    /// Get the number of strong references to this value.
+    pub fn strong_count(&self) -> usize {
+        count(self.box_ptr().data().state.load(SeqCst)) as usize
+    }
+
+    /// Get the number of weak references to this value.
+    pub fn weak_count(&self) -> usize {
+        self.box_ptr().weak_count()
+    }
+
+    /// Returns `true` if the value can be accessed through this `Cc<T>`, which
+    /// is only ever `false` while its garbage cycle is being dropped by
+    /// `sync::collect_cycles`.
+    pub fn is_alive(&self) -> bool {
+        self.strong_count() > 0
+    }
+
+    /// Borrow the value, or return `None` if it is not alive.
+    pub fn try_get(&self) -> Option<&T> {
+        if self.is_alive() {
+            unsafe { Some(&self.ptr.as_ref().value) }
+        } else {
+            None
+        }
+    }
+
+    /// Returns a mutable reference to the contained value if there are no
+    /// other `sync::Cc` or `sync::Weak` values that share it.
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        let b = self.box_ptr();
+        let data = b.data();
+        // Just like for `Arc<T>`, without other weak pointers nothing else can
+        // start pointing at the value, including the collector, which only
+        // finds boxes through strong references and the buffered roots.
+        if count(data.state.load(SeqCst)) == 1 && data.weak.load(SeqCst) == 1 {
+            unsafe { Some(&mut self.ptr.as_mut().value) }
+        } else {
+            None
+        }
+    }
+
+    /// Returns `true` if the two `sync::Cc`s point to the same allocation.
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        this.ptr == other.ptr
+    }
+
+    fn box_ptr(&self) -> BoxPtr {
+        BoxPtr(self.ptr.cast())
+    }
+}
+
+impl<T: Trace + Send + Sync> Deref for Cc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match self.try_get() {
+            Some(value) => value,
+            None => panic!("Invalid access during cycle collection"),
+        }
+    }
+}
+
+impl<T: Trace + Send + Sync> Clone for Cc<T> {
+    fn clone(&self) -> Cc<T> {
+        self.box_ptr().inc_strong();
+        Cc {
+            ptr: self.ptr,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T: Trace + Send + Sync> Drop for Cc<T> {
+    fn drop(&mut self) {
+        let b = self.box_ptr();
+        // Only the collector drops the references between the members of a
+        // garbage cycle, after zeroing their counts.
+        if count(b.data().state.load(SeqCst)) == 0 {
+            return;
+        }
+        if b.dec_strong() {
+            unsafe { b.release() };
+        } else {
+            b.buffer();
+        }
+    }
+}
+
+impl<T: Trace + Send + Sync> Trace for Cc<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer(CcBoxPtr::from_sync(self.ptr.cast()));
+    }
+}
+
+impl<T: fmt::Debug + Trace + Send + Sync> fmt::Debug for Cc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: fmt::Display + Trace + Send + Sync> fmt::Display for Cc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+/// A weak version of `sync::Cc<T>`.
+pub struct Weak<T: Trace + Send + Sync + 'static> {
+    ptr: NonNull<SyncBox<T>>,
+    marker: PhantomData<SyncBox<T>>,
+}
+
+unsafe impl<T: Trace + Send + Sync> Send for Weak<T> {}
+unsafe impl<T: Trace + Send + Sync> Sync for Weak<T> {}
+
+impl<T: Trace + Send + Sync> Weak<T> {
+    /// Constructs a new `sync::Weak<T>`, without allocating any memory.
+    /// Calling `upgrade` on the return value always gives `None`.
+    pub fn new() -> Weak<T> {
+        Weak {
+            // Like `Weak::new`, no box can live at `usize::MAX`.
+            ptr: unsafe { NonNull::new_unchecked(usize::MAX as *mut SyncBox<T>) },
+            marker: PhantomData,
+        }
+    }
+
+    /// Upgrades the `sync::Weak<T>` reference to a `sync::Cc<T>`, if the value
+    /// hasn't been dropped, nor is about to be by the collector.
+    pub fn upgrade(&self) -> Option<Cc<T>> {
+        if self.box_ptr()?.try_pin() {
+            Some(Cc {
+                ptr: self.ptr,
+                marker: PhantomData,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Get the number of strong references to the value, which is zero if it
+    /// has been dropped, or if this was created with `sync::Weak::new()`.
+    pub fn strong_count(&self) -> usize {
+        self.box_ptr()
+            .map_or(0, |b| count(b.data().state.load(SeqCst)) as usize)
+    }
+
+    /// Get the number of `sync::Weak<T>` references to the value, including
+    /// this one, or zero once no strong references remain.
+    pub fn weak_count(&self) -> usize {
+        match self.box_ptr() {
+            Some(b) if b.is_alive() => b.weak_count(),
+            _ => 0,
+        }
+    }
+
+    /// Returns `true` if the two `sync::Weak`s point to the same allocation,
+    /// or if both were created with `sync::Weak::new()`.
+    pub fn ptr_eq(&self, other: &Weak<T>) -> bool {
+        self.ptr == other.ptr
+    }
+
+    fn box_ptr(&self) -> Option<BoxPtr> {
+        if self.ptr.as_ptr() as usize == usize::MAX {
+            None
+        } else {
+            Some(BoxPtr(self.ptr.cast()))
+        }
+    }
+}
+
+impl<T: Trace + Send + Sync> Default for Weak<T> {
+    /// Constructs a new `sync::Weak<T>`, like `sync::Weak::new()`.
+    fn default() -> Weak<T> {
+        Weak::new()
+    }
+}
+
+impl<T: Trace + Send + Sync> Clone for Weak<T> {
+    fn clone(&self) -> Weak<T> {
+        if let Some(b) = self.box_ptr() {
+            b.data().weak.fetch_add(1, SeqCst);
+        }
+        Weak {
+            ptr: self.ptr,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T: Trace + Send + Sync> Drop for Weak<T> {
+    fn drop(&mut self) {
+        if let Some(b) = self.box_ptr() {
+            unsafe { b.release_weak() };
+        }
+    }
+}
+
+impl<T: Trace + Send + Sync> Trace for Weak<T> {
+    fn trace(&self, _tracer: &mut Tracer) {
+        // Weak references should not be traced.
+    }
+}
+
+impl<T: Trace + Send + Sync> fmt::Debug for Weak<T> {
+    /// Formats as `Weak(<alive>)`, `Weak(<dropped>)` or `Weak(<dangling>)`,
+    /// like `Weak<T>`, never formatting the referenced value.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.box_ptr() {
+            Some(b) if b.is_alive() => f.write_str("Weak(<alive>)"),
+            Some(_) => f.write_str("Weak(<dropped>)"),
+            None => f.write_str("Weak(<dangling>)"),
+        }
+    }
+}
+
+/// A mutual exclusion primitive for values in a `sync::Cc<T>`.
+///
+/// This wraps a `std::sync::Mutex<T>`, and lets the collector know when
+/// values may be changing, which is needed to safely collect cycles that go
+/// through it.
+pub struct Mutex<T: ?Sized> {
+    guards: Guards,
+    inner: std_sync::Mutex<T>,
+}
+
+/// The guard of a locked `sync::Mutex<T>`, which unlocks it when dropped.
+pub struct MutexGuard<'a, T: ?Sized + 'a> {
+    inner: std_sync::MutexGuard<'a, T>,
+    _active: Active<'a>,
+}
+
+/// The number of guards of a `sync::Mutex` that are alive, including those
+/// still waiting for the lock, and that were ever taken.
+struct Guards {
+    active: AtomicUsize,
+    taken: AtomicUsize,
+}
+
+/// One of the active guards of a `sync::Mutex`, counted until it's dropped,
+/// even if locking panics.
+struct Active<'a>(&'a AtomicUsize);
+
+impl<'a> Drop for Active<'a> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, SeqCst);
+    }
+}
+
+/// The mutexes seen by `Collection::validate`: the guards of each one and how
+/// many had been taken, and whether any was found locked.
+struct Validating {
+    seen: Vec<(*const Guards, usize)>,
+    locked: bool,
+}
+
+impl Guards {
+    /// Record this mutex if candidate cycles are being validated, see
+    /// `Collection::validate`.
+    fn observe(&self, locked: bool) {
+        let _ = VALIDATING.try_with(|v| {
+            if let Some(v) = v.borrow_mut().as_mut() {
+                // The opposite order of `Mutex::lock`.
+                let taken = self.taken.load(SeqCst);
+                v.locked |= locked || self.active.load(SeqCst) != 0;
+                v.seen.push((self, taken));
+            }
+        });
+    }
+}
+
+impl<T> Mutex<T> {
+    /// Creates a new, unlocked mutex.
+    pub fn new(value: T) -> Mutex<T> {
+        Mutex {
+            guards: Guards {
+                active: AtomicUsize::new(0),
+                taken: AtomicUsize::new(0),
+            },
+            inner: std_sync::Mutex::new(value),
+        }
+    }
+
+    /// Consumes the mutex, returning the value inside of it.
+    pub fn into_inner(self) -> LockResult<T> {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Acquires the mutex, blocking the current thread until it is able to
+    /// do so, like `std::sync::Mutex::lock`.
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
+        // See `Collection::validate` for why this is done in this order.
+        self.guards.active.fetch_add(1, SeqCst);
+        let _active = Active(&self.guards.active);
+        self.guards.taken.fetch_add(1, SeqCst);
+        match self.inner.lock() {
+            Ok(inner) => Ok(MutexGuard { inner, _active }),
+            Err(err) => Err(PoisonError::new(MutexGuard {
+                inner: err.into_inner(),
+                _active,
+            })),
+        }
+    }
+
+    /// Returns a mutable reference to the value. No locking is needed since
+    /// this takes `self` mutably.
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        self.inner.get_mut()
+    }
+}
+
+impl<'a, T: ?Sized> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: Trace + ?Sized> Trace for Mutex<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        // If the mutex is locked, the collector of the thread-safe heap won't
+        // trust what it sees anyway, and a thread-local collection might be
+        // running from under the guard, so its box must be treated as
+        // referenced from outside, like that of a borrowed `RefCell`.
+        let value = match self.inner.try_lock() {
+            Ok(value) => value,
+            Err(TryLockError::Poisoned(err)) => err.into_inner(),
+            Err(TryLockError::WouldBlock) => {
+                self.guards.observe(true);
+                crate::cc_box_ptr::skipped_borrowed();
+                return;
+            }
+        };
+        self.guards.observe(false);
+        value.trace(tracer);
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Mutex { .. }")
+    }
+}
+
+impl<'a, T: fmt::Debug + ?Sized> fmt::Debug for MutexGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// Return the number of possible cycle roots currently buffered for the
+/// collection of the thread-safe heap.
+pub fn number_of_roots_buffered() -> usize {
+    roots().len()
+}
+
+/// Collect the garbage cycles of the thread-safe heap.
+///
+/// This can be called from any thread, while other threads keep using their
+/// `sync::Cc<T>`s, and the destructors of the garbage are run on the calling
+/// thread. If another thread is already collecting, this waits for it to
+/// finish first. Calling this from a destructor run by a collection does
+/// nothing.
+///
+/// Candidate cycles that change while they are being checked, or one of whose
+/// `sync::Mutex`es is locked while they are, are buffered again and left for
+/// the next collection.
+///
+/// # Examples
+///
+/// ```
+/// use bacon_rajan_cc::sync;
+/// use std::sync::atomic::{AtomicBool, Ordering};
+/// use std::sync::Arc;
+/// use std::thread;
+/// use std::time::Duration;
+///
+/// // Collect cycles in the background, until told to stop.
+/// let stop = Arc::new(AtomicBool::new(false));
+/// let collector = {
+///     let stop = stop.clone();
+///     thread::spawn(move || {
+///         while !stop.load(Ordering::Relaxed) {
+///             sync::collect_cycles();
+///             thread::sleep(Duration::from_millis(1));
+///         }
+///     })
+/// };
+///
+/// // ...
+///
+/// stop.store(true, Ordering::Relaxed);
+/// collector.join().unwrap();
+/// ```
+pub fn collect_cycles() {
+    struct Collecting;
+
+    impl Drop for Collecting {
+        fn drop(&mut self) {
+            COLLECTING.with(|c| c.set(false));
+            TRACING.with(|t| t.set(false));
+        }
+    }
+
+    if COLLECTING.with(|c| c.replace(true)) {
+        return;
+    }
+    let _collecting = Collecting;
+    let _collector = COLLECTOR.lock().unwrap_or_else(PoisonError::into_inner);
+    // If a `Trace` impl panics, whatever we're holding on to is leaked.
+    let mut collection = Collection::start();
+    collection.mark_candidates();
+    collection.collect_candidates();
+    collection.finish();
+}
+
+/// The state of a collection: every box reachable from the roots it started
+/// from, each holding a strong reference taken by the collector.
+struct Collection {
+    nodes: Vec<BoxPtr>,
+    index: HashMap<BoxPtr, usize>,
+    color: Vec<Color>,
+    /// Whether a `sync::Mutex` of the node was found locked, in which case
+    /// it's buffered again, to be looked at once it's unlocked.
+    locked: Vec<bool>,
+    /// The first panic of a destructor of the garbage.
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+impl Collection {
+    /// Take the buffered roots, and trace everything that they reach.
+    fn start() -> Collection {
+        let old_roots = {
+            let mut roots = roots();
+            for s in roots.iter() {
+                s.data().buffered.store(false, SeqCst);
+            }
+            mem::take(&mut *roots)
+        };
+
+        let mut collection = Collection {
+            nodes: Vec::new(),
+            index: HashMap::new(),
+            color: Vec::new(),
+            locked: Vec::new(),
+            panic: None,
+        };
+        for s in old_roots {
+            collection.visit(s);
+            // Let go of the root buffer's weak reference.
+            unsafe { s.release_weak() };
+        }
+
+        let mut stack: Vec<usize> = (0..collection.nodes.len()).collect();
+        while let Some(i) = stack.pop() {
+            let s = collection.nodes[i];
+            let traced = s.trace_children(|t| {
+                // The child can't go away while we trace, since it is
+                // referenced from a value we hold on to, and any mutex it's in
+                // is locked.
+                if let Some(j) = collection.visit(t) {
+                    stack.push(j);
+                }
+            });
+            collection.locked[i] = !traced;
+        }
+        collection
+    }
+
+    /// Add `s` to the collection, if it's still alive and isn't already
+    /// part of it, returning its index.
+    fn visit(&mut self, s: BoxPtr) -> Option<usize> {
+        if self.index.contains_key(&s) || !s.try_pin() {
+            return None;
+        }
+        let i = self.nodes.len();
+        self.index.insert(s, i);
+        self.nodes.push(s);
+        self.color.push(Color::Gray);
+        self.locked.push(false);
+        Some(i)
+    }
+
+    /// Trial deletion on the cyclic reference counts: anything that has more
+    /// strong references than references from inside the collection is live,
+    /// and so is everything it reaches. The rest are candidate cycles, colored
+    /// Orange. The strong counts may change while we look, so this isn't
+    /// exact, which the Σ-test and Δ-test make up for.
+    fn mark_candidates(&mut self) {
+        let mut crc = vec![0; self.nodes.len()];
+        for (i, &s) in self.nodes.iter().enumerate() {
+            let index = &self.index;
+            let traced = s.trace_children(|t| {
+                if let Some(&j) = index.get(&t) {
+                    crc[j] += 1;
+                }
+            });
+            self.locked[i] |= !traced;
+        }
+
+        let mut stack = Vec::new();
+        for (i, s) in self.nodes.iter().enumerate() {
+            // Leave out the collector's own reference.
+            let strong = count(s.data().state.load(SeqCst)) - 1;
+            if strong != crc[i] {
+                self.color[i] = Color::Black;
+                stack.push(i);
+            }
+        }
+        while let Some(i) = stack.pop() {
+            let (index, color) = (&self.index, &mut self.color);
+            self.nodes[i].trace_children(|t| {
+                if let Some(&j) = index.get(&t) {
+                    if color[j] != Color::Black {
+                        color[j] = Color::Black;
+                        stack.push(j);
+                    }
+                }
+            });
+        }
+
+        for color in &mut self.color {
+            if *color == Color::Gray {
+                *color = Color::Orange;
+            }
+        }
+    }
+
+    /// Check the candidates, and free those that pass the Σ-test and the
+    /// Δ-test. Otherwise, buffer them again for the next collection.
+    fn collect_candidates(&mut self) {
+        let candidates: Vec<usize> = (0..self.nodes.len())
+            .filter(|&i| self.color[i] == Color::Orange)
+            .collect();
+        if candidates.is_empty() {
+            return;
+        }
+
+        match self.validate(&candidates) {
+            Some(garbage) => self.free(garbage),
+            None => {
+                for &i in &candidates {
+                    self.nodes[i].buffer();
+                }
+            }
+        }
+    }
+
+    /// Run the Σ-test and the Δ-test on the candidates, returning the ones
+    /// that are garbage along with their state, or `None` if something changed
+    /// while checking.
+    fn validate(&mut self, candidates: &[usize]) -> Option<Vec<(usize, u64)>> {
+        // Locking a `sync::Mutex` bumps the number of its active guards and
+        // then that of the ones taken, before locking. Reading them in the
+        // opposite order when tracing it here, and back in the same order at
+        // the end, tells us whether any guard of it was alive at any point in
+        // between, during which no reference can have moved in or out of it.
+        // Only the mutexes of the candidates matter: moving a reference in or
+        // out of any other one doesn't change what the candidates hold.
+        struct Validation;
+
+        impl Drop for Validation {
+            fn drop(&mut self) {
+                VALIDATING.with(|v| v.borrow_mut().take());
+            }
+        }
+
+        let _validation = Validation;
+        VALIDATING.with(|v| {
+            *v.borrow_mut() = Some(Validating {
+                seen: Vec::new(),
+                locked: false,
+            })
+        });
+
+        let position: HashMap<usize, usize> = candidates
+            .iter()
+            .enumerate()
+            .map(|(k, &i)| (i, k))
+            .collect();
+        let states: Vec<u64> = candidates
+            .iter()
+            .map(|&i| self.nodes[i].data().state.load(SeqCst))
+            .collect();
+        let mut crc = vec![0; candidates.len()];
+        let mut edges = vec![Vec::new(); candidates.len()];
+        for (k, &i) in candidates.iter().enumerate() {
+            self.color[i] = Color::Red;
+            let index = &self.index;
+            self.nodes[i].trace_children(|t| {
+                if let Some(l) = index.get(&t).and_then(|j| position.get(j)) {
+                    crc[*l] += 1;
+                    edges[k].push(*l);
+                }
+            });
+        }
+        let validating = VALIDATING.with(|v| v.borrow_mut().take());
+        let validating = validating.expect("validation in progress");
+        if validating.locked {
+            return None;
+        }
+
+        // Δ-test: if no count changed between the two loads, then they all
+        // held those values at once, in between. The references between the
+        // candidates couldn't change in the meantime either, since that needs
+        // either a mutex, or a strong reference to one of them.
+        for (k, &i) in candidates.iter().enumerate() {
+            if self.nodes[i].data().state.load(SeqCst) != states[k] {
+                return None;
+            }
+        }
+        for &(guards, taken) in &validating.seen {
+            // The candidates, and so their mutexes, are pinned by the
+            // collection.
+            let guards = unsafe { &*guards };
+            if guards.active.load(SeqCst) != 0 || guards.taken.load(SeqCst) != taken {
+                return None;
+            }
+        }
+
+        // Σ-test: candidates that are referenced from elsewhere are live, and
+        // so is everything they reach.
+        let mut live = vec![false; candidates.len()];
+        let mut stack = Vec::new();
+        for k in 0..candidates.len() {
+            if count(states[k]) - 1 != crc[k] {
+                live[k] = true;
+                stack.push(k);
+            }
+        }
+        while let Some(k) = stack.pop() {
+            for &l in &edges[k] {
+                if !live[l] {
+                    live[l] = true;
+                    stack.push(l);
+                }
+            }
+        }
+
+        let garbage: Vec<(usize, u64)> = (0..candidates.len())
+            .filter(|&k| !live[k])
+            .map(|k| (candidates[k], states[k]))
+            .collect();
+
+        // The garbage is unreachable now, but a `sync::Weak<T>` could still
+        // be upgraded. Marking stops that, and only succeeds if it didn't
+        // happen since the counts were loaded.
+        for (m, &(i, state)) in garbage.iter().enumerate() {
+            let data = self.nodes[i].data();
+            if data
+                .state
+                .compare_exchange(state, state | MARKED, SeqCst, SeqCst)
+                .is_err()
+            {
+                for &(j, _) in &garbage[..m] {
+                    self.nodes[j].data().state.fetch_and(!MARKED, SeqCst);
+                }
+                return None;
+            }
+        }
+        Some(garbage)
+    }
+
+    /// Drop the values of the garbage and free it.
+    fn free(&mut self, garbage: Vec<(usize, u64)>) {
+        let white: Vec<BoxPtr> = garbage.iter().map(|&(i, _)| self.nodes[i]).collect();
+        for &(i, _) in &garbage {
+            self.color[i] = Color::White;
+        }
+        // Zeroing the counts makes dropping the references between the
+        // members of the garbage a no-op. This also gets rid of our own.
+        for s in &white {
+            s.data().state.store(0, SeqCst);
+        }
+        for s in &white {
+            catch_panic(&mut self.panic, || unsafe { (s.data().vtable.drop_value)(*s) });
+        }
+        for s in white {
+            unsafe { s.release_weak() };
+        }
+    }
+
+    /// Let go of everything that wasn't freed, and then re-raise the first
+    /// panic of a destructor, if any.
+    fn finish(mut self) {
+        for ((s, color), locked) in self.nodes.into_iter().zip(self.color).zip(self.locked) {
+            if color != Color::White {
+                if locked {
+                    s.buffer();
+                }
+                catch_panic(&mut self.panic, || s.unpin());
+            }
+        }
+        if let Some(panic) = self.panic {
+            panic::resume_unwind(panic);
+        }
+    }
+}
@@ -7,13 +7,15 @@
 // or http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
-use std::ptr::NonNull;
-
 use crate::cc_box_ptr::CcBoxPtr;
 
 /// A `Tracer` is a callback function that is invoked for each `CcBoxPtr` owned
 /// by an instance of something.
-pub type Tracer<'a> = dyn FnMut(NonNull<dyn CcBoxPtr>) + 'a;
+///
+/// Since 0.5, a `CcBoxPtr` is an opaque, type erased pointer to a box, rather
+/// than a `NonNull<dyn CcBoxPtr>`; `Trace` impls should only ever pass the
+/// tracer on to the `Trace` impls of the values they own.
+pub type Tracer<'a> = dyn FnMut(CcBoxPtr) + 'a;
 
 /// A trait that informs cycle collector how to find memory that is owned by a
 /// `Trace` instance and managed by the cycle collector.
@@ -245,7 +247,7 @@ mod impls {
         use super::*;
         use std::cell;
 
-        impl<T: Copy + Trace + ?Sized> Trace for cell::Cell<T> {
+        impl<T: Copy + Trace> Trace for cell::Cell<T> {
             fn trace(&self, tracer: &mut Tracer) {
                 self.get().trace(tracer);
             }
@@ -253,9 +255,17 @@ mod impls {
 
         impl<T: Trace + ?Sized> Trace for cell::RefCell<T> {
             fn trace(&self, tracer: &mut Tracer) {
-                // We'll panic if we can't borrow. I'm not
-                // sure if we have a better option.
-                self.borrow().trace(tracer);
+                // A `RefCell` can only be mutably borrowed through a live
+                // reference to its owner, so the owner can't be garbage. This
+                // happens with automatic collection, which may run while a
+                // `Cc<T>` is dropped from inside such a borrow. The contents
+                // can't be traced then, so instead the collectors are told to
+                // treat the owner as an external root: it's kept alive, along
+                // with everything it references.
+                match self.try_borrow() {
+                    Ok(value) => value.trace(tracer),
+                    Err(_) => crate::cc_box_ptr::skipped_borrowed(),
+                }
             }
         }
     }
@@ -460,6 +470,17 @@ mod impls {
         }
     }
 
+    mod mem {
+        use super::*;
+        use std::mem;
+
+        // The contents may not be initialized yet, so they must never be
+        // traced. `Cc::assume_init` switches a box over to tracing its `T`.
+        impl<T> Trace for mem::MaybeUninit<T> {
+            fn trace(&self, _tracer: &mut Tracer) {}
+        }
+    }
+
     mod option {
         use super::*;
 
@@ -578,6 +599,11 @@ mod impls {
 
         impl<T: Trace> Trace for sync::RwLock<T> {
             fn trace(&self, tracer: &mut Tracer) {
+                // The collector of the thread-safe heap can't tell when the
+                // contents change, so to it, they're referenced from outside.
+                if crate::sync::is_tracing() {
+                    return;
+                }
                 if let Ok(v) = self.write() {
                     v.trace(tracer);
                 }